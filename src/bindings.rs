@@ -0,0 +1,394 @@
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+
+use minifb::Key;
+
+// Buttons of the console, in the order of their bit in the joypad rows
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    pub fn is_direction(&self) -> bool {
+        matches!(
+            self,
+            Button::Right | Button::Left | Button::Up | Button::Down
+        )
+    }
+
+    // Bit of the button in its joypad row
+    pub fn mask(&self) -> u8 {
+        match self {
+            Button::Right | Button::A => 0b1,
+            Button::Left | Button::B => 0b10,
+            Button::Up | Button::Select => 0b100,
+            Button::Down | Button::Start => 0b1000,
+        }
+    }
+}
+
+impl fmt::Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Button::Right => "right",
+            Button::Left => "left",
+            Button::Up => "up",
+            Button::Down => "down",
+            Button::A => "a",
+            Button::B => "b",
+            Button::Select => "select",
+            Button::Start => "start",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Button {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Button::ALL
+            .iter()
+            .find(|button| button.to_string() == name.to_lowercase())
+            .copied()
+            .ok_or(format!("Unknown button: {}", name))
+    }
+}
+
+// What a host key can be bound to. Turbo buttons are pressed and released
+// repeatedly while the key is held down
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Input {
+    Button(Button),
+    Turbo(Button),
+}
+
+impl Input {
+    pub const TURBO: [Input; 2] = [Input::Turbo(Button::A), Input::Turbo(Button::B)];
+
+    pub fn button(&self) -> Button {
+        match self {
+            Input::Button(button) | Input::Turbo(button) => *button,
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Button(button) => write!(f, "{}", button),
+            Input::Turbo(button) => write!(f, "turbo_{}", button),
+        }
+    }
+}
+
+impl FromStr for Input {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().strip_prefix("turbo_") {
+            Some("a") => Ok(Input::Turbo(Button::A)),
+            Some("b") => Ok(Input::Turbo(Button::B)),
+            Some(_) => Err(format!("Only A and B have a turbo: {}", name)),
+            None => Ok(Input::Button(name.parse()?)),
+        }
+    }
+}
+
+// Host keys that can be bound, by their name in the config file
+const KEY_NAMES: [(&str, Key); 62] = [
+    ("a", Key::A),
+    ("b", Key::B),
+    ("c", Key::C),
+    ("d", Key::D),
+    ("e", Key::E),
+    ("f", Key::F),
+    ("g", Key::G),
+    ("h", Key::H),
+    ("i", Key::I),
+    ("j", Key::J),
+    ("k", Key::K),
+    ("l", Key::L),
+    ("m", Key::M),
+    ("n", Key::N),
+    ("o", Key::O),
+    ("p", Key::P),
+    ("q", Key::Q),
+    ("r", Key::R),
+    ("s", Key::S),
+    ("t", Key::T),
+    ("u", Key::U),
+    ("v", Key::V),
+    ("w", Key::W),
+    ("x", Key::X),
+    ("y", Key::Y),
+    ("z", Key::Z),
+    ("0", Key::Key0),
+    ("1", Key::Key1),
+    ("2", Key::Key2),
+    ("3", Key::Key3),
+    ("4", Key::Key4),
+    ("5", Key::Key5),
+    ("6", Key::Key6),
+    ("7", Key::Key7),
+    ("8", Key::Key8),
+    ("9", Key::Key9),
+    ("up", Key::Up),
+    ("down", Key::Down),
+    ("left", Key::Left),
+    ("right", Key::Right),
+    ("enter", Key::Enter),
+    ("space", Key::Space),
+    ("backspace", Key::Backspace),
+    ("tab", Key::Tab),
+    ("escape", Key::Escape),
+    ("leftshift", Key::LeftShift),
+    ("rightshift", Key::RightShift),
+    ("leftctrl", Key::LeftCtrl),
+    ("rightctrl", Key::RightCtrl),
+    ("leftalt", Key::LeftAlt),
+    ("rightalt", Key::RightAlt),
+    ("numpad0", Key::NumPad0),
+    ("numpad1", Key::NumPad1),
+    ("numpad2", Key::NumPad2),
+    ("numpad3", Key::NumPad3),
+    ("numpad4", Key::NumPad4),
+    ("numpad5", Key::NumPad5),
+    ("numpad6", Key::NumPad6),
+    ("numpad7", Key::NumPad7),
+    ("numpad8", Key::NumPad8),
+    ("numpad9", Key::NumPad9),
+    ("numpadenter", Key::NumPadEnter),
+];
+
+fn parse_key(name: &str) -> Result<Key, String> {
+    KEY_NAMES
+        .iter()
+        .find(|(key_name, _)| *key_name == name.to_lowercase())
+        .map(|(_, key)| *key)
+        .ok_or(format!("Unknown key: {}", name))
+}
+
+fn key_name(key: Key) -> &'static str {
+    KEY_NAMES
+        .iter()
+        .find(|(_, named_key)| *named_key == key)
+        .map_or("?", |(name, _)| name)
+}
+
+// Host keys bound to each input, any of them presses the button
+pub struct KeyBindings {
+    bindings: Vec<(Input, Vec<Key>)>,
+    turbo_rate: u32,
+}
+
+impl KeyBindings {
+    pub const DEFAULT_PATH: &'static str = "keys.cfg";
+    const DEFAULT_TURBO_RATE: u32 = 4;
+
+    // Read bindings from a config file with one `button = key, key...` line per
+    // button, `#` starts a comment. Buttons missing from the file keep their defaults.
+    // `turbo_rate = frames` sets how many frames turbo buttons stay pressed, then released
+    pub fn from_file(path: &str) -> Result<KeyBindings, String> {
+        let content =
+            fs::read_to_string(path).map_err(|err| format!("Could not read {}: {}", path, err))?;
+        KeyBindings::parse(&content, path)
+    }
+
+    // Errors are prefixed with the name of the config and the line number
+    pub(crate) fn parse(content: &str, path: &str) -> Result<KeyBindings, String> {
+        let mut key_bindings = KeyBindings::default();
+
+        for (index, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("").trim();
+            if line.is_empty() {
+                continue;
+            }
+            let error = |err: String| format!("{}:{}: {}", path, index + 1, err);
+            let (button, keys) = line
+                .split_once('=')
+                .ok_or_else(|| error(String::from("expected `button = key, key...`")))?;
+            if button.trim() == "turbo_rate" {
+                key_bindings.turbo_rate = match keys.trim().parse() {
+                    Ok(rate) if rate > 0 => rate,
+                    _ => return Err(error(format!("Invalid turbo rate: {}", keys.trim()))),
+                };
+                continue;
+            }
+            let input: Input = button.trim().parse().map_err(error)?;
+            let keys = keys
+                .split(',')
+                .map(|key| parse_key(key.trim()))
+                .collect::<Result<Vec<Key>, String>>()
+                .map_err(error)?;
+            key_bindings.bind(input, keys);
+        }
+
+        Ok(key_bindings)
+    }
+
+    pub fn bind(&mut self, input: Input, keys: Vec<Key>) {
+        match self.bindings.iter_mut().find(|(bound, _)| *bound == input) {
+            Some((_, bound_keys)) => *bound_keys = keys,
+            None => self.bindings.push((input, keys)),
+        }
+    }
+
+    pub fn keys(&self, input: Input) -> &[Key] {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == input)
+            .map_or(&[], |(_, keys)| &keys[..])
+    }
+
+    pub fn turbo_rate(&self) -> u32 {
+        self.turbo_rate
+    }
+
+    // Describe every host key bound to several buttons, and the keys
+    // which are already used by the emulator itself
+    pub fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = Vec::new();
+        for (index, (input, keys)) in self.bindings.iter().enumerate() {
+            for key in keys {
+                if *key == Key::Escape {
+                    conflicts.push(format!(
+                        "escape is bound to {} but also quits the emulator",
+                        input
+                    ));
+                }
+                for (other_input, other_keys) in &self.bindings[index + 1..] {
+                    if other_keys.contains(key) {
+                        conflicts.push(format!(
+                            "{} is bound to both {} and {}",
+                            key_name(*key),
+                            input,
+                            other_input
+                        ));
+                    }
+                }
+            }
+        }
+        conflicts
+    }
+}
+
+impl Default for KeyBindings {
+    fn default() -> Self {
+        KeyBindings {
+            bindings: vec![
+                (Input::Button(Button::Right), vec![Key::Right]),
+                (Input::Button(Button::Left), vec![Key::Left]),
+                (Input::Button(Button::Up), vec![Key::Up]),
+                (Input::Button(Button::Down), vec![Key::Down]),
+                (Input::Button(Button::A), vec![Key::A]),
+                (Input::Button(Button::B), vec![Key::B]),
+                (Input::Button(Button::Select), vec![Key::Space]),
+                (Input::Button(Button::Start), vec![Key::Enter]),
+                (Input::Turbo(Button::A), vec![Key::S]),
+                (Input::Turbo(Button::B), vec![Key::Z]),
+            ],
+            turbo_rate: KeyBindings::DEFAULT_TURBO_RATE,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bindings() {
+        let content = "# comment\n\na = x, Enter # trailing comment\nTURBO_B = q\nturbo_rate = 2\n";
+        let key_bindings = KeyBindings::parse(content, "keys.cfg").unwrap();
+        assert_eq!(
+            key_bindings.keys(Input::Button(Button::A)),
+            [Key::X, Key::Enter]
+        );
+        assert_eq!(key_bindings.keys(Input::Turbo(Button::B)), [Key::Q]);
+        assert_eq!(key_bindings.keys(Input::Button(Button::B)), [Key::B]);
+        assert_eq!(key_bindings.turbo_rate(), 2);
+    }
+
+    #[test]
+    fn reports_missing_separator() {
+        let error = KeyBindings::parse("a\n\nstart enter", "keys.cfg").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("keys.cfg:1: expected `button = key, key...`")
+        );
+    }
+
+    #[test]
+    fn reports_invalid_turbo_rate() {
+        for rate in &["0", "-1", "fast"] {
+            let error = KeyBindings::parse(&format!("turbo_rate = {}", rate), "keys.cfg").err();
+            assert_eq!(
+                error,
+                Some(format!("keys.cfg:1: Invalid turbo rate: {}", rate))
+            );
+        }
+    }
+
+    #[test]
+    fn reports_unknown_button() {
+        let error = KeyBindings::parse("a = x\nturbo = y", "keys.cfg").err();
+        assert_eq!(error.as_deref(), Some("keys.cfg:2: Unknown button: turbo"));
+    }
+
+    #[test]
+    fn reports_turbo_direction() {
+        let error = KeyBindings::parse("turbo_up = w", "keys.cfg").err();
+        assert_eq!(
+            error.as_deref(),
+            Some("keys.cfg:1: Only A and B have a turbo: turbo_up")
+        );
+    }
+
+    #[test]
+    fn reports_unknown_key() {
+        let error = KeyBindings::parse("select = space, f13", "keys.cfg").err();
+        assert_eq!(error.as_deref(), Some("keys.cfg:1: Unknown key: f13"));
+    }
+
+    #[test]
+    fn reports_unreadable_file() {
+        let error = KeyBindings::from_file("/nonexistent/keys.cfg")
+            .err()
+            .unwrap();
+        assert!(error.starts_with("Could not read /nonexistent/keys.cfg: "));
+    }
+
+    #[test]
+    fn finds_conflicts() {
+        let mut key_bindings = KeyBindings::default();
+        key_bindings.bind(Input::Button(Button::Start), vec![Key::A, Key::Escape]);
+        assert_eq!(
+            key_bindings.conflicts(),
+            [
+                "a is bound to both a and start",
+                "escape is bound to start but also quits the emulator"
+            ]
+        );
+    }
+}
@@ -1,22 +1,97 @@
+mod apu;
+mod cartridge;
+mod dma;
+mod joypad;
+mod mapper;
+mod mbc1;
+mod mbc2;
+mod mbc3;
+mod mbc5;
+mod serial;
+mod timer;
+
 use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::canvas::Canvas;
+use crate::gpu::GPU;
+use crate::model::Model;
+
+use apu::APU;
+pub use cartridge::*;
+use dma::Dma;
+use joypad::Joypad;
+use mapper::*;
+use serial::Serial;
+use timer::Timer;
 
+#[allow(clippy::upper_case_acronyms)]
 struct ROM {
     cartridge: Vec<u8>,
+    header: CartridgeHeader,
+    mapper: Box<dyn Mapper>,
 }
 
 impl ROM {
-    pub fn from_file(filename: &String) -> ROM {
-        ROM {
-            cartridge: match fs::read(filename) {
-                Err(err) => panic!("Could not read content of {} : {}", filename, err),
-                Ok(file) => file,
-            },
-        }
+    pub fn from_file(filename: &str) -> Result<ROM, CartridgeError> {
+        ROM::from_data(fs::read(filename)?)
+    }
+
+    fn from_data(cartridge: Vec<u8>) -> Result<ROM, CartridgeError> {
+        let header = CartridgeHeader::parse(&cartridge)?;
+        let mapper = create_mapper(&header, &cartridge)?;
+
+        Ok(ROM {
+            cartridge,
+            header,
+            mapper,
+        })
+    }
+
+    fn get_byte(&self, address: u16) -> u8 {
+        // bank numbers wrap around the actual ROM size
+        self.cartridge[self.mapper.rom_offset(address) % self.cartridge.len()]
+    }
+
+    fn set_byte(&mut self, address: u16, data: u8) {
+        self.mapper.write_register(address, data)
+    }
+
+    fn get_ram_byte(&self, address: u16) -> u8 {
+        self.mapper.read_ram(address)
+    }
+
+    fn set_ram_byte(&mut self, address: u16, data: u8) -> bool {
+        self.mapper.write_ram(address, data)
     }
+}
+
+// Battery backed cartridges keep their state in a file next to the ROM
+pub fn save_file_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("sav")
+}
+
+// DMG boot ROMs are 256 bytes long, CGB ones have an extra part mapped at 0x0200
+pub fn load_boot_rom(filename: &str, model: Model) -> Result<Vec<u8>, String> {
+    let image = fs::read(filename).map_err(|err| err.to_string())?;
+    check_boot_rom_size(&image, model)?;
 
-    fn get_byte(&self, i: u16) -> u8 {
-        self.cartridge[i as usize]
+    Ok(image)
+}
+
+// The image has to match the model, a CGB one would hide the cartridge of a DMG
+fn check_boot_rom_size(image: &[u8], model: Model) -> Result<(), String> {
+    let size = if model.is_cgb() { 0x900 } else { 0x100 };
+    if image.len() != size {
+        return Err(format!(
+            "invalid size of {} bytes, a {} boot ROM takes {}",
+            image.len(),
+            model,
+            size
+        ));
     }
+
+    Ok(())
 }
 
 struct WorkingRam {
@@ -47,74 +122,659 @@ impl WorkingRam {
 
 pub struct Bus {
     rom: ROM,
-    vram: WorkingRam,
-    external_ram: WorkingRam,
     wram1: WorkingRam,
     wram2: WorkingRam,
-    oam: WorkingRam,
-    io: WorkingRam,
+    io: WorkingRam, // registers without a dedicated component
     high_ram: WorkingRam,
+    gpu: GPU,
+    timer: Timer,
+    joypad: Joypad,
+    serial: Serial,
+    apu: APU,
+    dma: Dma,
+    model: Model,
+    interrupt_flag: u8,
+    new_interrupts: u8,   // requested by the components during the last M-cycle
+    early_interrupts: u8, // requested during the first half of the last M-cycle
     interrupt_enable_register: u8,
+    boot_rom: Option<Vec<u8>>,
+    rumble: bool,
+    cycles_since_ram_write: Option<u32>,
+    cycles: u64,          // clock cycles since power on
+    vblank_entered: bool, // set when the PPU enters VBlank, until polled
 }
 
 impl Bus {
-    pub fn new_bus(filename: &String) -> Bus {
-        Bus {
-            rom: ROM::from_file(filename),
-            vram: WorkingRam::from_size(8192, 0x8000),
-            external_ram: WorkingRam::from_size(8192, 0xA000),
+    const BOOT_ROM_DISABLE: u16 = 0xFF50;
+    const SAVE_DELAY: u32 = 3 * 4194304; // 3 seconds
+    const INTERRUPT_FLAG: u16 = 0xFF0F;
+
+    const VBLANK_INTERRUPT: u8 = 0b1;
+    const LCD_STAT_INTERRUPT: u8 = 0b10;
+    const TIMER_INTERRUPT: u8 = 0b100;
+    const SERIAL_INTERRUPT: u8 = 0b1000;
+    const JOYPAD_INTERRUPT: u8 = 0b10000;
+
+    // When a boot ROM image is given, it is mapped over the cartridge until the
+    // boot sequence writes to 0xFF50. Otherwise the bus starts in post-boot state
+    pub fn new_bus(
+        filename: &str,
+        model: Model,
+        boot_rom: Option<Vec<u8>>,
+    ) -> Result<Bus, CartridgeError> {
+        Ok(Bus::with_rom(ROM::from_file(filename)?, model, boot_rom))
+    }
+
+    // Bus for a cartridge image already in memory, without boot ROM
+    #[cfg(test)]
+    pub fn from_cartridge(cartridge: Vec<u8>, model: Model) -> Result<Bus, CartridgeError> {
+        Ok(Bus::with_rom(ROM::from_data(cartridge)?, model, None))
+    }
+
+    fn with_rom(rom: ROM, model: Model, boot_rom: Option<Vec<u8>>) -> Bus {
+        let mut bus = Bus {
+            rom,
             wram1: WorkingRam::from_size(4096, 0xC000),
             wram2: WorkingRam::from_size(4096, 0xD000),
-            oam: WorkingRam::from_size(160, 0xFE00),
             io: WorkingRam::from_size(128, 0xFF00),
             high_ram: WorkingRam::from_size(127, 0xFF80),
+            gpu: GPU::new(),
+            timer: Timer::new(0),
+            joypad: Joypad::new(),
+            serial: Serial::new(model.is_cgb()),
+            apu: APU::new(),
+            dma: Dma::new(0),
+            model,
+            interrupt_flag: 0,
+            new_interrupts: 0,
+            early_interrupts: 0,
             interrupt_enable_register: 0,
+            boot_rom,
+            rumble: false,
+            cycles_since_ram_write: None,
+            cycles: 0,
+            vblank_entered: false,
+        };
+
+        for address in 0xFF00..=0xFF7F {
+            bus.io.set_byte(address, 0xFF);
+        }
+        // components start in power on state, the boot ROM sets them up
+        if bus.boot_rom.is_none() {
+            bus.init_io_registers(model);
+        }
+
+        bus
+    }
+
+    pub fn cartridge_header(&self) -> &CartridgeHeader {
+        &self.rom.header
+    }
+
+    pub fn cartridge_data(&self) -> &[u8] {
+        &self.rom.cartridge
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    // A M-cycle of CPU time is 4 clock cycles
+    pub fn tick_m_cycle(&mut self) {
+        self.new_interrupts = 0;
+        self.tick();
+        self.tick();
+        self.early_interrupts = self.new_interrupts;
+        self.tick();
+        self.tick();
+        if let Some((source, index)) = self.dma.tick() {
+            let data = self.fetch_byte(source);
+            self.gpu.write_oam(GPU::OAM + index as u16, data);
+        }
+    }
+
+    // Memory accesses from the CPU take a M-cycle each
+    pub fn read_cycle(&mut self, address: u16) -> u8 {
+        self.tick_m_cycle();
+        // the CPU loses the bus to the OAM DMA and reads the byte being moved,
+        // OAM itself can't be accessed at all
+        if self.dma.is_conflicting(address) {
+            return match self.dma.current_source() {
+                Some(source) if address < GPU::OAM => self.fetch_byte(source),
+                _ => 0xFF,
+            };
+        }
+        self.fetch_byte(address)
+    }
+
+    pub fn read_word_cycles(&mut self, address: u16) -> u16 {
+        let lower = self.read_cycle(address);
+        let higher = self.read_cycle(address.wrapping_add(1));
+        ((higher as u16) << 8) + (lower as u16)
+    }
+
+    pub fn write_cycle(&mut self, address: u16, data: u8) {
+        self.tick_m_cycle();
+        if !self.dma.is_conflicting(address) {
+            self.set_byte(address, data);
+        }
+    }
+
+    // Advance every component by one clock cycle
+    fn tick(&mut self) {
+        self.cycles += 1;
+        let mut interrupts = 0;
+        if self.timer.tick() {
+            interrupts |= Bus::TIMER_INTERRUPT;
+        }
+        if self.serial.tick() {
+            interrupts |= Bus::SERIAL_INTERRUPT;
+        }
+        let gpu_interrupts = self.gpu.tick();
+        if gpu_interrupts.vblank {
+            interrupts |= Bus::VBLANK_INTERRUPT;
+            self.vblank_entered = true;
+        }
+        if gpu_interrupts.stat {
+            interrupts |= Bus::LCD_STAT_INTERRUPT;
+        }
+        self.request_interrupt(interrupts);
+        self.new_interrupts |= interrupts;
+        self.tick_cartridge();
+    }
+
+    // STOP halts the system clock: the timer, the PPU and the rest of the
+    // console freeze, while the cartridge keeps its own time
+    pub fn tick_stopped_m_cycle(&mut self) {
+        for _ in 0..4 {
+            self.cycles += 1;
+            self.tick_cartridge();
+        }
+    }
+
+    fn tick_cartridge(&mut self) {
+        self.rom.mapper.tick(1);
+        if let Some(cycles) = self.cycles_since_ram_write.as_mut() {
+            *cycles += 1;
+        }
+    }
+
+    pub fn reset_divider(&mut self) {
+        self.timer.write(Timer::DIV, 0);
+    }
+
+    // True once the cartridge RAM has been left untouched for a while after
+    // being written, so that saves are flushed without waiting for exit
+    pub fn is_save_due(&mut self) -> bool {
+        match self.cycles_since_ram_write {
+            Some(cycles) if cycles >= Bus::SAVE_DELAY => {
+                self.cycles_since_ram_write = None;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn request_interrupt(&mut self, interrupt: u8) {
+        self.interrupt_flag |= interrupt;
+    }
+
+    // Requested by the components during the last M-cycle, too late for the
+    // CPU to notice them yet. The timer and the PPU are the exceptions, they
+    // are still in time
+    fn late_interrupts(&self) -> u8 {
+        self.new_interrupts
+            & !(Bus::TIMER_INTERRUPT | Bus::LCD_STAT_INTERRUPT | Bus::VBLANK_INTERRUPT)
+    }
+
+    // Interrupts the CPU can dispatch at the end of an instruction
+    pub fn dispatchable_interrupts(&self) -> u8 {
+        self.interrupt_enable_register & self.interrupt_flag & !self.late_interrupts() & 0b11111
+    }
+
+    // Clear the request of a serviced interrupt, unless it came again late
+    pub fn acknowledge_interrupt(&mut self, interrupt: u8) {
+        self.interrupt_flag &= !interrupt | self.late_interrupts();
+    }
+
+    // Timer interrupts requested early enough during an opcode fetch for the
+    // CPU to service them instead of that opcode
+    pub fn early_interrupts(&self) -> u8 {
+        self.interrupt_enable_register & self.early_interrupts & Bus::TIMER_INTERRUPT
+    }
+
+    pub fn screen(&self) -> &Canvas {
+        self.gpu.screen()
+    }
+
+    // Update the keys held down, one bit per key with 0 meaning pressed
+    pub fn set_joypad_keys(&mut self, directions: u8, buttons: u8) {
+        if self.joypad.set_keys(directions, buttons) {
+            self.request_interrupt(Bus::JOYPAD_INTERRUPT);
+        }
+    }
+
+    // A selected key held down brings the CPU out of STOP
+    pub fn is_joypad_pressed(&self) -> bool {
+        self.joypad.is_pressed()
+    }
+
+    // True once the PPU entered VBlank since the last call
+    pub fn poll_vblank(&mut self) -> bool {
+        let vblank = self.vblank_entered;
+        self.vblank_entered = false;
+        vblank
+    }
+
+    // Returns the new state of the rumble motor when it changed since the last call
+    pub fn poll_rumble(&mut self) -> Option<bool> {
+        let rumble = self.rom.mapper.rumble();
+        if rumble == self.rumble {
+            return None;
+        }
+        self.rumble = rumble;
+        Some(rumble)
+    }
+
+    pub fn save_data(&self) -> Vec<u8> {
+        self.rom.mapper.save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.rom.mapper.load_save_data(data)
+    }
+
+    pub fn catch_up_clock(&mut self) {
+        self.rom.mapper.catch_up_clock()
+    }
+
+    pub fn is_header_checksum_valid(&self) -> bool {
+        self.rom
+            .header
+            .is_header_checksum_valid(&self.rom.cartridge)
+    }
+
+    pub fn is_global_checksum_valid(&self) -> bool {
+        self.rom
+            .header
+            .is_global_checksum_valid(&self.rom.cartridge)
+    }
+
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom.is_some()
+    }
+
+    // The CGB boot ROM is split in two, leaving the cartridge header visible
+    fn fetch_boot_rom_byte(&self, address: u16) -> Option<u8> {
+        match (&self.boot_rom, address) {
+            (_, 0x0100..=0x01FF) => None,
+            (Some(boot_rom), _) => boot_rom.get(address as usize).copied(),
+            (None, _) => None,
+        }
+    }
+
+    // Set hardware registers to the values left behind by the boot ROM
+    fn init_io_registers(&mut self, model: Model) {
+        // DIV depends on how long the boot ROM ran
+        self.timer = Timer::new(match model {
+            Model::DMG0 => 0x182C,
+            Model::DMG | Model::MGB => 0xABC8,
+            Model::SGB | Model::SGB2 => 0xD85C,
+            Model::CGB => 0x1EA0,
+        });
+
+        // NR52 first, the APU ignores writes while powered off
+        let registers: [(u16, u8); 35] = [
+            (0xFF00, if model.is_sgb() { 0xFF } else { 0xCF }), // P1
+            (0xFF01, 0x00),                                     // SB
+            (0xFF02, if model.is_cgb() { 0x7F } else { 0x7E }), // SC
+            (0xFF0F, 0xE1),                                     // IF
+            (0xFF26, 0x80),                                     // NR52
+            (0xFF10, 0x80),                                     // NR10
+            (0xFF11, 0xBF),                                     // NR11
+            (0xFF12, 0xF3),                                     // NR12
+            (0xFF13, 0xFF),                                     // NR13
+            (0xFF14, 0xBF),                                     // NR14
+            (0xFF16, 0x3F),                                     // NR21
+            (0xFF17, 0x00),                                     // NR22
+            (0xFF18, 0xFF),                                     // NR23
+            (0xFF19, 0xBF),                                     // NR24
+            (0xFF1A, 0x7F),                                     // NR30
+            (0xFF1B, 0xFF),                                     // NR31
+            (0xFF1C, 0x9F),                                     // NR32
+            (0xFF1D, 0xFF),                                     // NR33
+            (0xFF1E, 0xBF),                                     // NR34
+            (0xFF20, 0xFF),                                     // NR41
+            (0xFF21, 0x00),                                     // NR42
+            (0xFF22, 0x00),                                     // NR43
+            (0xFF23, 0xBF),                                     // NR44
+            (0xFF24, 0x77),                                     // NR50
+            (0xFF25, 0xF3),                                     // NR51
+            (0xFF40, 0x91),                                     // LCDC
+            (0xFF41, 0x85),                                     // STAT
+            (0xFF42, 0x00),                                     // SCY
+            (0xFF43, 0x00),                                     // SCX
+            (0xFF45, 0x00),                                     // LYC
+            (0xFF47, 0xFC),                                     // BGP
+            (0xFF48, 0xFF),                                     // OBP0
+            (0xFF49, 0xFF),                                     // OBP1
+            (0xFF4A, 0x00),                                     // WY
+            (0xFF4B, 0x00),                                     // WX
+        ];
+        for (address, value) in registers.iter() {
+            self.set_byte(*address, *value);
+        }
+        if !model.is_sgb() {
+            self.apu.set_channels_on(0b1);
+        }
+        self.dma = Dma::new(if model.is_cgb() { 0x00 } else { 0xFF });
+
+        // the DMG0 boot ROM takes longer, it hands over in the middle of line 145
+        // while the others leave the LCD as if it was just turned on
+        if model == Model::DMG0 {
+            for _ in 0..GPU::DMG0_BOOT_CLOCKS {
+                self.gpu.tick();
+            }
+        }
+
+        if model.is_cgb() {
+            self.io.set_byte(0xFF4D, 0x7E); // KEY1
+            self.io.set_byte(0xFF4F, 0xFE); // VBK
+            self.io.set_byte(0xFF56, 0x3E); // RP
+            self.io.set_byte(0xFF70, 0xF8); // SVBK
         }
     }
 
     pub fn fetch_byte(&self, address: u16) -> u8 {
+        if let Some(data) = self.fetch_boot_rom_byte(address) {
+            return data;
+        }
+
         match address {
-            /*0x0000..=0x3FFF => self.rom.get_byte(address),
-            0x4000..=0x7FFF => 0, // ROM bank 1..N in cartridge*/
             0x0000..=0x7FFF => self.rom.get_byte(address),
-            0x8000..=0x9FFF => self.vram.get_byte(address),
-            0xA000..=0xBFFF => self.external_ram.get_byte(address),
+            0x8000..=0x9FFF => self.gpu.read_vram(address),
+            0xA000..=0xBFFF => self.rom.get_ram_byte(address),
             0xC000..=0xCFFF => self.wram1.get_byte(address),
             0xD000..=0xDFFF => self.wram2.get_byte(address),
-            0xE000..=0xFDFF => self.wram1.get_byte(address),
-            0xFE00..=0xFE9F => self.oam.get_byte(address),
+            // echo of 0xC000-0xDDFF
+            0xE000..=0xEFFF => self.wram1.get_byte(address - 0x2000),
+            0xF000..=0xFDFF => self.wram2.get_byte(address - 0x2000),
+            0xFE00..=0xFE9F => self.gpu.read_oam(address),
             0xFEA0..=0xFEFF => 0, //panic!("Address {:#x} is not usable !", address),
-            0xFF00..=0xFF7F => self.io.get_byte(address),
+            Joypad::REGISTER => self.joypad.read(),
+            Serial::DATA..=Serial::CONTROL => self.serial.read(address),
+            Timer::DIV..=Timer::TAC => self.timer.read(address),
+            Bus::INTERRUPT_FLAG => self.interrupt_flag | 0b11100000,
+            APU::START..=APU::END => self.apu.read(address),
+            GPU::CONTROL_REGISTER..=GPU::Y_COMPARE | GPU::BG_PALETTE..=GPU::WINDOW_X => {
+                self.gpu.read_register(address)
+            }
+            Dma::REGISTER => self.dma.read(),
+            _ if self.is_cgb_register(address) => self.io.get_byte(address),
             0xFF80..=0xFFFE => self.high_ram.get_byte(address),
             0xFFFF => self.interrupt_enable_register,
+            // unmapped registers read as 0xFF
+            _ => 0xFF,
         }
     }
 
-    pub fn fetch_word(&self, address: u16) -> u16 {
-        let lower = self.fetch_byte(address);
-        let higher = self.fetch_byte(address + 1);
-        ((higher as u16) << 8) + (lower as u16)
-    }
-
     pub fn set_byte(&mut self, address: u16, data: u8) {
         match address {
-            0x0000..=0x3FFF => {}
-            0x4000..=0x7FFF => panic!("ROM banks not supported !"), // ROM bank 1..N in cartridge
-            0x8000..=0x9FFF => self.vram.set_byte(address, data),
-            0xA000..=0xBFFF => self.external_ram.set_byte(address, data),
+            0x0000..=0x7FFF => self.rom.set_byte(address, data),
+            0x8000..=0x9FFF => self.gpu.write_vram(address, data),
+            0xA000..=0xBFFF => {
+                // only writes that change the battery backed state call for a save
+                let changed = self.rom.set_ram_byte(address, data);
+                if changed {
+                    self.cycles_since_ram_write = Some(0);
+                }
+            }
             0xC000..=0xCFFF => self.wram1.set_byte(address, data),
             0xD000..=0xDFFF => self.wram2.set_byte(address, data),
-            0xE000..=0xFDFF => self.wram1.set_byte(address, data),
-            0xFE00..=0xFE9F => self.oam.set_byte(address, data),
+            0xE000..=0xEFFF => self.wram1.set_byte(address - 0x2000, data),
+            0xF000..=0xFDFF => self.wram2.set_byte(address - 0x2000, data),
+            0xFE00..=0xFE9F => self.gpu.write_oam(address, data),
             0xFEA0..=0xFEFF => {} //panic!("Address {:#x} is not usable !", address),
-            0xFF00..=0xFF7F => self.io.set_byte(address, data),
+            Bus::BOOT_ROM_DISABLE => self.boot_rom = None,
+            Joypad::REGISTER => {
+                let falling_edge = self.joypad.write(data);
+                if falling_edge {
+                    self.request_interrupt(Bus::JOYPAD_INTERRUPT);
+                }
+            }
+            Serial::DATA..=Serial::CONTROL => self.serial.write(address, data),
+            Timer::DIV..=Timer::TAC => self.timer.write(address, data),
+            Bus::INTERRUPT_FLAG => {
+                // a timer interrupt requested during the same M-cycle wins
+                let timer = self.new_interrupts & Bus::TIMER_INTERRUPT;
+                self.interrupt_flag = data & 0b11111 | timer;
+                self.new_interrupts = 0;
+            }
+            APU::START..=APU::END => self.apu.write(address, data),
+            GPU::CONTROL_REGISTER..=GPU::Y_COMPARE | GPU::BG_PALETTE..=GPU::WINDOW_X => {
+                let stat = self.gpu.write_register(address, data);
+                if stat {
+                    self.request_interrupt(Bus::LCD_STAT_INTERRUPT);
+                }
+            }
+            Dma::REGISTER => self.dma.write(data),
+            _ if self.is_cgb_register(address) => self.io.set_byte(address, data),
             0xFF80..=0xFFFE => self.high_ram.set_byte(address, data),
             0xFFFF => self.interrupt_enable_register = data,
+            _ => {}
         }
     }
 
-    pub fn set_word(&mut self, address: u16, data: u16) {
-        self.set_byte(address, (data & 0xFF) as u8);
-        self.set_byte(address + 1, ((data & 0xFF00) >> 8) as u8);
+    // Registers that only exist on CGB, they have no component behind them yet
+    fn is_cgb_register(&self, address: u16) -> bool {
+        self.model.is_cgb()
+            && matches!(
+                address,
+                0xFF4D | 0xFF4F | 0xFF51..=0xFF56 | 0xFF68..=0xFF6C | 0xFF70 | 0xFF72..=0xFF77
+            )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bus_with_interrupts(enabled: u8, requested: u8, new: u8) -> Bus {
+        let mut bus = Bus::from_cartridge(vec![0; 0x8000], Model::DMG).unwrap();
+        bus.interrupt_enable_register = enabled;
+        bus.interrupt_flag = requested;
+        bus.new_interrupts = new;
+        bus
+    }
+
+    #[test]
+    fn dispatches_enabled_interrupts_only() {
+        let bus = bus_with_interrupts(Bus::TIMER_INTERRUPT, 0b11111, 0);
+        assert_eq!(bus.dispatchable_interrupts(), Bus::TIMER_INTERRUPT);
+        let bus = bus_with_interrupts(0xFF, 0xE0, 0);
+        assert_eq!(bus.dispatchable_interrupts(), 0);
+    }
+
+    #[test]
+    fn delays_interrupts_requested_during_last_m_cycle() {
+        for &interrupt in &[Bus::SERIAL_INTERRUPT, Bus::JOYPAD_INTERRUPT] {
+            let bus = bus_with_interrupts(0xFF, interrupt, interrupt);
+            assert_eq!(bus.dispatchable_interrupts(), 0);
+        }
+        // the timer and the PPU are still in time
+        for &interrupt in &[
+            Bus::VBLANK_INTERRUPT,
+            Bus::LCD_STAT_INTERRUPT,
+            Bus::TIMER_INTERRUPT,
+        ] {
+            let bus = bus_with_interrupts(0xFF, interrupt, interrupt);
+            assert_eq!(bus.dispatchable_interrupts(), interrupt);
+        }
+        // a serial interrupt requested earlier is not hidden by a new one
+        let bus = bus_with_interrupts(0xFF, Bus::SERIAL_INTERRUPT, Bus::JOYPAD_INTERRUPT);
+        assert_eq!(bus.dispatchable_interrupts(), Bus::SERIAL_INTERRUPT);
+    }
+
+    #[test]
+    fn keeps_interrupt_requested_again_while_acknowledged() {
+        let requested = Bus::VBLANK_INTERRUPT | Bus::SERIAL_INTERRUPT;
+        let mut bus = bus_with_interrupts(0xFF, requested, Bus::SERIAL_INTERRUPT);
+        bus.acknowledge_interrupt(Bus::SERIAL_INTERRUPT);
+        assert_eq!(bus.interrupt_flag, requested);
+        bus.acknowledge_interrupt(Bus::VBLANK_INTERRUPT);
+        assert_eq!(bus.interrupt_flag, Bus::SERIAL_INTERRUPT);
+    }
+
+    fn bus_with_boot_rom(model: Model, boot_rom: Vec<u8>) -> Bus {
+        let mut cartridge = vec![0; 0x8000];
+        cartridge[0x0005] = 0xCA;
+        cartridge[0x0150] = 0xFE;
+        cartridge[0x0250] = 0xBA;
+        Bus::with_rom(ROM::from_data(cartridge).unwrap(), model, Some(boot_rom))
+    }
+
+    #[test]
+    fn maps_the_boot_rom_over_the_cartridge() {
+        let mut bus = bus_with_boot_rom(Model::CGB, vec![0x31; 0x900]);
+        assert!(bus.is_boot_rom_mapped());
+        assert_eq!(bus.fetch_byte(0x0005), 0x31);
+        assert_eq!(bus.fetch_byte(0x00FF), 0x31);
+        // the CGB boot ROM leaves the cartridge header visible
+        assert_eq!(bus.fetch_byte(0x0150), 0xFE);
+        assert_eq!(bus.fetch_byte(0x0250), 0x31);
+
+        bus.set_byte(Bus::BOOT_ROM_DISABLE, 0x11);
+        assert!(!bus.is_boot_rom_mapped());
+        assert_eq!(bus.fetch_byte(0x0005), 0xCA);
+        assert_eq!(bus.fetch_byte(0x0250), 0xBA);
+        // there is no way back
+        bus.set_byte(Bus::BOOT_ROM_DISABLE, 0x00);
+        assert_eq!(bus.fetch_byte(0x0005), 0xCA);
+    }
+
+    #[test]
+    fn maps_the_dmg_boot_rom_over_the_first_256_bytes() {
+        let bus = bus_with_boot_rom(Model::DMG, vec![0x31; 0x100]);
+        assert_eq!(bus.fetch_byte(0x0005), 0x31);
+        assert_eq!(bus.fetch_byte(0x0150), 0xFE);
+        assert_eq!(bus.fetch_byte(0x0250), 0xBA);
+    }
+
+    #[test]
+    fn rejects_boot_roms_of_another_model() {
+        assert!(check_boot_rom_size(&[0; 0x100], Model::DMG).is_ok());
+        assert!(check_boot_rom_size(&[0; 0x900], Model::CGB).is_ok());
+        assert!(check_boot_rom_size(&[0; 0x900], Model::DMG).is_err());
+        assert!(check_boot_rom_size(&[0; 0x100], Model::CGB).is_err());
+        assert!(check_boot_rom_size(&[0; 0x200], Model::SGB).is_err());
+    }
+
+    fn bus_with_cartridge_type(cartridge_type: u8, ram_size: u8) -> Bus {
+        let mut cartridge = vec![0; 0x8000];
+        cartridge[0x0147] = cartridge_type;
+        cartridge[0x0149] = ram_size;
+        Bus::from_cartridge(cartridge, Model::DMG).unwrap()
+    }
+
+    fn tick_m_cycles(bus: &mut Bus, clocks: u32) {
+        for _ in 0..clocks / 4 {
+            bus.tick_m_cycle();
+        }
+    }
+
+    #[test]
+    fn schedules_a_save_after_cartridge_ram_writes() {
+        // MBC1 with 8 KiB of battery backed RAM
+        let mut bus = bus_with_cartridge_type(0x03, 0x02);
+        bus.set_byte(0x0000, 0x0A);
+        tick_m_cycles(&mut bus, Bus::SAVE_DELAY);
+        assert!(!bus.is_save_due());
+
+        bus.set_byte(0xA000, 0x42);
+        tick_m_cycles(&mut bus, Bus::SAVE_DELAY / 2);
+        // another write pushes the save back
+        bus.set_byte(0xA001, 0x42);
+        tick_m_cycles(&mut bus, Bus::SAVE_DELAY - 4);
+        assert!(!bus.is_save_due());
+        tick_m_cycles(&mut bus, 4);
+        assert!(bus.is_save_due());
+        // the save is only reported once
+        assert!(!bus.is_save_due());
+        tick_m_cycles(&mut bus, Bus::SAVE_DELAY);
+        assert!(!bus.is_save_due());
+    }
+
+    #[test]
+    fn never_schedules_a_save_without_cartridge_ram() {
+        let mut bus = bus_with_cartridge_type(0x01, 0x00);
+        bus.set_byte(0x0000, 0x0A);
+        bus.set_byte(0xA000, 0x42);
+        tick_m_cycles(&mut bus, Bus::SAVE_DELAY);
+        assert!(!bus.is_save_due());
+        assert!(bus.save_data().is_empty());
+    }
+
+    #[test]
+    fn loads_saves_of_the_wrong_size() {
+        // MBC1, MBC2 and MBC3 with its clock
+        for &(cartridge_type, ram_size) in &[(0x03, 0x02), (0x06, 0x00), (0x10, 0x03)] {
+            let mut bus = bus_with_cartridge_type(cartridge_type, ram_size);
+            let size = bus.save_data().len();
+            bus.load_save_data(&[0x05; 3]);
+            assert_eq!(bus.save_data().len(), size);
+            bus.load_save_data(&vec![0x05; size + 100]);
+            assert_eq!(bus.save_data().len(), size);
+            assert_eq!(bus.save_data()[0], 0x05);
+        }
+    }
+
+    #[test]
+    fn reads_unused_register_bits_as_1() {
+        // address, then what it reads back after writing 0 on DMG and CGB
+        let registers = [
+            (0xFF00, 0xCF, 0xCF), // P1
+            (0xFF01, 0x00, 0x00), // SB
+            (0xFF02, 0x7E, 0x7C), // SC
+            (0xFF07, 0xF8, 0xF8), // TAC
+            (0xFF0F, 0xE0, 0xE0), // IF
+            (0xFF10, 0x80, 0x80), // NR10
+            (0xFF11, 0x3F, 0x3F), // NR11
+            (0xFF12, 0x00, 0x00), // NR12
+            (0xFF13, 0xFF, 0xFF), // NR13
+            (0xFF14, 0xBF, 0xBF), // NR14
+            (0xFF1A, 0x7F, 0x7F), // NR30
+            (0xFF1C, 0x9F, 0x9F), // NR32
+            (0xFF20, 0xFF, 0xFF), // NR41
+            (0xFF23, 0xBF, 0xBF), // NR44
+            (0xFF24, 0x00, 0x00), // NR50
+            (0xFF26, 0x70, 0x70), // NR52, powering the APU off
+            (0xFF30, 0x00, 0x00), // wave RAM
+        ];
+        for &(model, column) in &[(Model::DMG, 0), (Model::CGB, 1)] {
+            let mut bus = Bus::from_cartridge(vec![0; 0x8000], model).unwrap();
+            for &(address, dmg, cgb) in &registers {
+                bus.set_byte(address, 0x00);
+                let expected = [dmg, cgb][column];
+                assert_eq!(
+                    bus.fetch_byte(address),
+                    expected,
+                    "{} {:#06x}",
+                    model,
+                    address
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn reads_unmapped_registers_as_0xff() {
+        let mut bus = Bus::from_cartridge(vec![0; 0x8000], Model::DMG).unwrap();
+        let unmapped = [
+            0xFF03, 0xFF08, 0xFF0E, 0xFF15, 0xFF1F, 0xFF27, 0xFF2F, 0xFF4C, 0xFF4D, 0xFF7F,
+        ];
+        for &address in &unmapped {
+            bus.set_byte(address, 0x00);
+            assert_eq!(bus.fetch_byte(address), 0xFF, "{:#06x}", address);
+        }
     }
 }
@@ -0,0 +1,73 @@
+// Sound registers. Channels are not emulated yet, only the register file is:
+// unused bits read as 1 and nothing but NR52 can be written while powered off
+#[allow(clippy::upper_case_acronyms)]
+pub struct APU {
+    registers: [u8; APU::REGISTER_COUNT],
+    wave_ram: [u8; 16],
+    enabled: bool,
+    channels_on: u8, // NR52 bits 0-3, read only
+}
+
+impl APU {
+    pub const START: u16 = 0xFF10;
+    pub const END: u16 = 0xFF3F;
+    const SOUND_ON: u16 = 0xFF26; // NR52
+    const WAVE_RAM: u16 = 0xFF30;
+    const REGISTER_COUNT: usize = (APU::SOUND_ON - APU::START) as usize;
+
+    // Bits reading as 1 for NR10-NR51, write only fields included
+    const READ_MASKS: [u8; APU::REGISTER_COUNT] = [
+        0x80, 0x3F, 0x00, 0xFF, 0xBF, // NR10-NR14
+        0xFF, 0x3F, 0x00, 0xFF, 0xBF, // NR20-NR24
+        0x7F, 0xFF, 0x9F, 0xFF, 0xBF, // NR30-NR34
+        0xFF, 0xFF, 0x00, 0x00, 0xBF, // NR40-NR44
+        0x00, 0x00, // NR50-NR51
+    ];
+
+    pub fn new() -> Self {
+        APU {
+            registers: [0; APU::REGISTER_COUNT],
+            wave_ram: [0; 16],
+            enabled: false,
+            channels_on: 0,
+        }
+    }
+
+    // The boot ROM leaves channel 1 running after playing its sound
+    pub fn set_channels_on(&mut self, channels: u8) {
+        self.channels_on = channels & 0b1111;
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            APU::START..=0xFF25 => {
+                let index = (address - APU::START) as usize;
+                self.registers[index] | APU::READ_MASKS[index]
+            }
+            APU::SOUND_ON => {
+                let enabled = if self.enabled { 0b10000000 } else { 0 };
+                enabled | 0b1110000 | self.channels_on
+            }
+            APU::WAVE_RAM..=APU::END => self.wave_ram[(address - APU::WAVE_RAM) as usize],
+            _ => 0xFF,
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            APU::START..=0xFF25 if self.enabled => {
+                self.registers[(address - APU::START) as usize] = data
+            }
+            APU::SOUND_ON => {
+                self.enabled = data & 0b10000000 != 0;
+                if !self.enabled {
+                    // powering off clears every register
+                    self.registers = [0; APU::REGISTER_COUNT];
+                    self.channels_on = 0;
+                }
+            }
+            APU::WAVE_RAM..=APU::END => self.wave_ram[(address - APU::WAVE_RAM) as usize] = data,
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,483 @@
+use std::fmt;
+use std::fmt::{Display, Formatter};
+use std::io;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Truncated { expected: usize, actual: usize },
+    InvalidCartridgeType(u8),
+    InvalidRomSize(u8),
+    InvalidRamSize(u8),
+    UnsupportedController(Controller),
+}
+
+impl Display for CartridgeError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "could not read cartridge: {}", err),
+            CartridgeError::Truncated { expected, actual } => write!(
+                f,
+                "cartridge is truncated: expected {} bytes, got {}",
+                expected, actual
+            ),
+            CartridgeError::InvalidCartridgeType(code) => {
+                write!(f, "invalid cartridge type {:#04x}", code)
+            }
+            CartridgeError::InvalidRomSize(code) => write!(f, "invalid ROM size {:#04x}", code),
+            CartridgeError::InvalidRamSize(code) => write!(f, "invalid RAM size {:#04x}", code),
+            CartridgeError::UnsupportedController(controller) => {
+                write!(
+                    f,
+                    "memory bank controller {:?} is not supported",
+                    controller
+                )
+            }
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Controller {
+    RomOnly,
+    MBC1,
+    MBC2,
+    MBC3,
+    MBC5,
+    MBC6,
+    MBC7,
+    MMM01,
+    PocketCamera,
+    TAMA5,
+    HuC3,
+    HuC1,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct CartridgeType {
+    pub code: u8,
+    pub controller: Controller,
+    pub ram: bool,
+    pub battery: bool,
+    pub timer: bool,
+    pub rumble: bool,
+}
+
+impl CartridgeType {
+    pub fn from_code(code: u8) -> Result<Self, CartridgeError> {
+        use Controller::*;
+        // (controller, ram, battery, timer, rumble)
+        let (controller, ram, battery, timer, rumble) = match code {
+            0x00 => (RomOnly, false, false, false, false),
+            0x01 => (MBC1, false, false, false, false),
+            0x02 => (MBC1, true, false, false, false),
+            0x03 => (MBC1, true, true, false, false),
+            0x05 => (MBC2, false, false, false, false),
+            0x06 => (MBC2, false, true, false, false),
+            0x08 => (RomOnly, true, false, false, false),
+            0x09 => (RomOnly, true, true, false, false),
+            0x0B => (MMM01, false, false, false, false),
+            0x0C => (MMM01, true, false, false, false),
+            0x0D => (MMM01, true, true, false, false),
+            0x0F => (MBC3, false, true, true, false),
+            0x10 => (MBC3, true, true, true, false),
+            0x11 => (MBC3, false, false, false, false),
+            0x12 => (MBC3, true, false, false, false),
+            0x13 => (MBC3, true, true, false, false),
+            0x19 => (MBC5, false, false, false, false),
+            0x1A => (MBC5, true, false, false, false),
+            0x1B => (MBC5, true, true, false, false),
+            0x1C => (MBC5, false, false, false, true),
+            0x1D => (MBC5, true, false, false, true),
+            0x1E => (MBC5, true, true, false, true),
+            0x20 => (MBC6, true, true, false, false),
+            0x22 => (MBC7, true, true, false, true),
+            0xFC => (PocketCamera, true, true, false, false),
+            0xFD => (TAMA5, true, true, false, false),
+            0xFE => (HuC3, true, true, true, false),
+            0xFF => (HuC1, true, true, false, false),
+            _ => return Err(CartridgeError::InvalidCartridgeType(code)),
+        };
+
+        Ok(CartridgeType {
+            code,
+            controller,
+            ram,
+            battery,
+            timer,
+            rumble,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    Compatible, // works on DMG too
+    Only,
+}
+
+#[derive(Debug, Clone)]
+pub enum Licensee {
+    Old(u8),
+    New(String), // old code 0x33 means the two ASCII bytes at 0x144 are used
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+}
+
+#[derive(Debug, Clone)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer_code: Option<String>,
+    pub cgb_support: CgbSupport,
+    pub licensee: Licensee,
+    pub sgb_support: bool,
+    pub cartridge_type: CartridgeType,
+    pub rom_size: usize,
+    pub ram_size: usize,
+    pub destination: Destination,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl CartridgeHeader {
+    const TITLE: usize = 0x134;
+    const MANUFACTURER_CODE: usize = 0x13F;
+    const CGB_FLAG: usize = 0x143;
+    const NEW_LICENSEE_CODE: usize = 0x144;
+    const SGB_FLAG: usize = 0x146;
+    const CARTRIDGE_TYPE: usize = 0x147;
+    const ROM_SIZE: usize = 0x148;
+    const RAM_SIZE: usize = 0x149;
+    const DESTINATION: usize = 0x14A;
+    const OLD_LICENSEE_CODE: usize = 0x14B;
+    const VERSION: usize = 0x14C;
+    const HEADER_CHECKSUM: usize = 0x14D;
+    const GLOBAL_CHECKSUM: usize = 0x14E;
+    const END: usize = 0x150;
+
+    pub fn parse(data: &[u8]) -> Result<Self, CartridgeError> {
+        if data.len() < CartridgeHeader::END {
+            return Err(CartridgeError::Truncated {
+                expected: CartridgeHeader::END,
+                actual: data.len(),
+            });
+        }
+
+        let rom_size_code = data[CartridgeHeader::ROM_SIZE];
+        let rom_size = match rom_size_code {
+            0x00..=0x08 => 0x8000 << rom_size_code,
+            _ => return Err(CartridgeError::InvalidRomSize(rom_size_code)),
+        };
+        if data.len() < rom_size {
+            return Err(CartridgeError::Truncated {
+                expected: rom_size,
+                actual: data.len(),
+            });
+        }
+
+        let ram_size_code = data[CartridgeHeader::RAM_SIZE];
+        let ram_size = match ram_size_code {
+            0x00 => 0,
+            0x01 => 0x800, // unofficial, only seen in homebrew
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            _ => return Err(CartridgeError::InvalidRamSize(ram_size_code)),
+        };
+
+        let cgb_support = match data[CartridgeHeader::CGB_FLAG] {
+            0xC0 => CgbSupport::Only,
+            flag if flag & 0x80 != 0 => CgbSupport::Compatible,
+            _ => CgbSupport::None,
+        };
+
+        // CGB cartridges shortened the title to make room for the manufacturer code
+        let manufacturer_code = match cgb_support {
+            CgbSupport::None => None,
+            _ => {
+                let code = &data[CartridgeHeader::MANUFACTURER_CODE..CartridgeHeader::CGB_FLAG];
+                if code
+                    .iter()
+                    .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit())
+                {
+                    Some(String::from_utf8_lossy(code).into_owned())
+                } else {
+                    None
+                }
+            }
+        };
+        let title_end = match (cgb_support, &manufacturer_code) {
+            (_, Some(_)) => CartridgeHeader::MANUFACTURER_CODE,
+            (CgbSupport::None, None) => CartridgeHeader::NEW_LICENSEE_CODE,
+            (_, None) => CartridgeHeader::CGB_FLAG,
+        };
+        let title = data[CartridgeHeader::TITLE..title_end]
+            .iter()
+            .take_while(|c| **c != 0)
+            .map(|c| *c as char)
+            .collect();
+
+        let licensee = match data[CartridgeHeader::OLD_LICENSEE_CODE] {
+            0x33 => Licensee::New(
+                String::from_utf8_lossy(
+                    &data[CartridgeHeader::NEW_LICENSEE_CODE..CartridgeHeader::SGB_FLAG],
+                )
+                .into_owned(),
+            ),
+            code => Licensee::Old(code),
+        };
+
+        let global_checksum = ((data[CartridgeHeader::GLOBAL_CHECKSUM] as u16) << 8)
+            + data[CartridgeHeader::GLOBAL_CHECKSUM + 1] as u16;
+
+        Ok(CartridgeHeader {
+            title,
+            manufacturer_code,
+            cgb_support,
+            licensee,
+            sgb_support: data[CartridgeHeader::SGB_FLAG] == 0x03,
+            cartridge_type: CartridgeType::from_code(data[CartridgeHeader::CARTRIDGE_TYPE])?,
+            rom_size,
+            ram_size,
+            destination: match data[CartridgeHeader::DESTINATION] {
+                0x00 => Destination::Japan,
+                _ => Destination::Overseas,
+            },
+            version: data[CartridgeHeader::VERSION],
+            header_checksum: data[CartridgeHeader::HEADER_CHECKSUM],
+            global_checksum,
+        })
+    }
+
+    // Checked by the boot ROM, which locks up if it does not match. Without a
+    // boot ROM nothing checks it, so patched ROMs with a stale one still run
+    pub fn is_header_checksum_valid(&self, data: &[u8]) -> bool {
+        CartridgeHeader::compute_header_checksum(data) == self.header_checksum
+    }
+
+    fn compute_header_checksum(data: &[u8]) -> u8 {
+        data[CartridgeHeader::TITLE..CartridgeHeader::HEADER_CHECKSUM]
+            .iter()
+            .fold(0u8, |sum, byte| sum.wrapping_sub(*byte).wrapping_sub(1))
+    }
+
+    // Sum of every byte of the ROM except the checksum itself, never checked by hardware
+    pub fn is_global_checksum_valid(&self, data: &[u8]) -> bool {
+        let sum = data
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| {
+                *i != CartridgeHeader::GLOBAL_CHECKSUM && *i != CartridgeHeader::GLOBAL_CHECKSUM + 1
+            })
+            .fold(0u16, |sum, (_, byte)| sum.wrapping_add(*byte as u16));
+
+        sum == self.global_checksum
+    }
+}
+
+impl Display for CartridgeHeader {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(f, "Title: {}", self.title)?;
+        if let Some(code) = &self.manufacturer_code {
+            writeln!(f, "Manufacturer: {}", code)?;
+        }
+        match &self.licensee {
+            Licensee::Old(code) => writeln!(f, "Licensee: {:#04x}", code)?,
+            Licensee::New(code) => writeln!(f, "Licensee: {}", code)?,
+        }
+        writeln!(
+            f,
+            "Type: {:#04x} {:?}{}{}{}{}",
+            self.cartridge_type.code,
+            self.cartridge_type.controller,
+            if self.cartridge_type.ram { "+RAM" } else { "" },
+            if self.cartridge_type.battery {
+                "+BATTERY"
+            } else {
+                ""
+            },
+            if self.cartridge_type.timer {
+                "+TIMER"
+            } else {
+                ""
+            },
+            if self.cartridge_type.rumble {
+                "+RUMBLE"
+            } else {
+                ""
+            },
+        )?;
+        writeln!(
+            f,
+            "ROM: {} KiB, RAM: {} KiB",
+            self.rom_size / 1024,
+            self.ram_size / 1024
+        )?;
+        writeln!(
+            f,
+            "CGB: {:?}, SGB: {}, Destination: {:?}, Version: {}",
+            self.cgb_support, self.sgb_support, self.destination, self.version
+        )?;
+        write!(
+            f,
+            "Header checksum: {:#04x}, Global checksum: {:#06x}",
+            self.header_checksum, self.global_checksum
+        )
+    }
+}
+
+// Header of a cartridge of the given type and sizes, to build mappers in tests
+#[cfg(test)]
+pub(crate) fn test_header(cartridge_type: u8, rom_size: usize, ram_size: usize) -> CartridgeHeader {
+    CartridgeHeader {
+        title: String::new(),
+        manufacturer_code: None,
+        cgb_support: CgbSupport::None,
+        licensee: Licensee::Old(0),
+        sgb_support: false,
+        cartridge_type: CartridgeType::from_code(cartridge_type).unwrap(),
+        rom_size,
+        ram_size,
+        destination: Destination::Japan,
+        version: 0,
+        header_checksum: 0,
+        global_checksum: 0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bus::mapper::create_mapper;
+
+    // 32 KiB ROM only image with a valid header checksum
+    fn rom_image() -> Vec<u8> {
+        let mut data = vec![0; 0x8000];
+        data[CartridgeHeader::TITLE..CartridgeHeader::TITLE + 4].copy_from_slice(b"TEST");
+        fix_checksum(&mut data);
+        data
+    }
+
+    fn fix_checksum(data: &mut [u8]) {
+        data[CartridgeHeader::HEADER_CHECKSUM] = CartridgeHeader::compute_header_checksum(data);
+    }
+
+    #[test]
+    fn parses_valid_header() {
+        let mut data = rom_image();
+        data[CartridgeHeader::CARTRIDGE_TYPE] = 0x13;
+        data[CartridgeHeader::RAM_SIZE] = 0x03;
+        data[CartridgeHeader::OLD_LICENSEE_CODE] = 0x01;
+        fix_checksum(&mut data);
+
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert_eq!(header.title, "TEST");
+        assert_eq!(header.cartridge_type.controller, Controller::MBC3);
+        assert!(header.cartridge_type.ram && header.cartridge_type.battery);
+        assert_eq!(header.rom_size, 0x8000);
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.cgb_support, CgbSupport::None);
+        assert_eq!(header.destination, Destination::Japan);
+    }
+
+    #[test]
+    fn rejects_data_shorter_than_header() {
+        let data = rom_image();
+        match CartridgeHeader::parse(&data[..0x14F]) {
+            Err(CartridgeError::Truncated { expected, actual }) => {
+                assert_eq!((expected, actual), (0x150, 0x14F))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rejects_data_shorter_than_rom_size() {
+        let mut data = rom_image();
+        data[CartridgeHeader::ROM_SIZE] = 0x01;
+        fix_checksum(&mut data);
+        match CartridgeHeader::parse(&data) {
+            Err(CartridgeError::Truncated { expected, actual }) => {
+                assert_eq!((expected, actual), (0x10000, 0x8000))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn accepts_bad_header_checksum() {
+        let mut data = rom_image();
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(header.is_header_checksum_valid(&data));
+
+        let checksum = data[CartridgeHeader::HEADER_CHECKSUM];
+        data[CartridgeHeader::HEADER_CHECKSUM] = checksum.wrapping_add(1);
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert_eq!(header.header_checksum, checksum.wrapping_add(1));
+        assert!(!header.is_header_checksum_valid(&data));
+    }
+
+    #[test]
+    fn rejects_invalid_rom_size() {
+        let mut data = rom_image();
+        data[CartridgeHeader::ROM_SIZE] = 0x09;
+        fix_checksum(&mut data);
+        assert!(matches!(
+            CartridgeHeader::parse(&data),
+            Err(CartridgeError::InvalidRomSize(0x09))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_ram_size() {
+        let mut data = rom_image();
+        data[CartridgeHeader::RAM_SIZE] = 0x06;
+        fix_checksum(&mut data);
+        assert!(matches!(
+            CartridgeHeader::parse(&data),
+            Err(CartridgeError::InvalidRamSize(0x06))
+        ));
+    }
+
+    #[test]
+    fn rejects_invalid_cartridge_type() {
+        let mut data = rom_image();
+        data[CartridgeHeader::CARTRIDGE_TYPE] = 0x04;
+        fix_checksum(&mut data);
+        assert!(matches!(
+            CartridgeHeader::parse(&data),
+            Err(CartridgeError::InvalidCartridgeType(0x04))
+        ));
+    }
+
+    #[test]
+    fn rejects_unsupported_controller() {
+        let mut data = rom_image();
+        data[CartridgeHeader::CARTRIDGE_TYPE] = 0xFE;
+        fix_checksum(&mut data);
+        let header = CartridgeHeader::parse(&data).unwrap();
+        assert!(matches!(
+            create_mapper(&header, &data),
+            Err(CartridgeError::UnsupportedController(Controller::HuC3))
+        ));
+    }
+
+    #[test]
+    fn converts_io_errors() {
+        let err = CartridgeError::from(io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(err, CartridgeError::Io(_)));
+    }
+}
@@ -0,0 +1,179 @@
+// OAM DMA. Writing XX to 0xFF46 copies 0xXX00-0xXX9F to OAM, one byte per
+// M-cycle once a M-cycle of setup went by. Meanwhile the DMA owns the bus it
+// reads from, either the external one (cartridge and WRAM) or the VRAM one
+pub struct Dma {
+    source: u8,                     // last value written to the register
+    starting: Option<(u8, u8)>,     // source of a requested transfer, setup cycles left
+    transfer: Option<(u16, usize)>, // source address and index of the next byte
+}
+
+impl Dma {
+    pub const REGISTER: u16 = 0xFF46;
+
+    const SETUP_CYCLES: u8 = 1;
+    const LENGTH: usize = 0xA0;
+
+    pub fn new(source: u8) -> Self {
+        Dma {
+            source,
+            starting: None,
+            transfer: None,
+        }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.source
+    }
+
+    // A transfer requested while another one runs replaces it after the setup
+    pub fn write(&mut self, data: u8) {
+        self.source = data;
+        self.starting = Some((data, Dma::SETUP_CYCLES));
+    }
+
+    // True when the CPU can't access the address because of the transfer.
+    // OAM is always busy, HRAM and the registers have their own bus
+    pub fn is_conflicting(&self, address: u16) -> bool {
+        let source = match self.transfer {
+            Some((source, _)) => source,
+            None => return false,
+        };
+        match address {
+            0xFE00..=0xFEFF => true,
+            0xFF00..=0xFFFF => false,
+            _ => Dma::is_vram(address) == Dma::is_vram(source),
+        }
+    }
+
+    // Address of the byte moved during the current M-cycle, which is what the
+    // CPU sees when reading from the same bus
+    pub fn current_source(&self) -> Option<u16> {
+        self.transfer
+            .map(|(source, index)| source + index as u16 - 1)
+    }
+
+    fn is_vram(address: u16) -> bool {
+        (0x8000..=0x9FFF).contains(&address)
+    }
+
+    // Advance by one M-cycle, returns the address to copy from and the OAM
+    // offset to copy to when a byte is transferred during this cycle
+    pub fn tick(&mut self) -> Option<(u16, usize)> {
+        if let Some((_, Dma::LENGTH)) = self.transfer {
+            self.transfer = None;
+        }
+        match self.starting {
+            Some((source, 0)) => {
+                self.starting = None;
+                self.transfer = Some((Dma::source_address(source), 0));
+            }
+            Some((source, cycles)) => self.starting = Some((source, cycles - 1)),
+            None => {}
+        }
+
+        let (address, index) = self.transfer.as_mut()?;
+        let byte = (*address + *index as u16, *index);
+        *index += 1;
+        Some(byte)
+    }
+
+    // The DMA sees the echo of WRAM above 0xDFFF, up to 0xFFFF
+    fn source_address(source: u8) -> u16 {
+        let address = (source as u16) << 8;
+        if address >= 0xE000 {
+            address - 0x2000
+        } else {
+            address
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(dma: &mut Dma, cycles: usize) -> Vec<Option<(u16, usize)>> {
+        (0..cycles).map(|_| dma.tick()).collect()
+    }
+
+    #[test]
+    fn starts_after_a_setup_cycle() {
+        let mut dma = Dma::new(0);
+        dma.write(0xC1);
+        assert_eq!(dma.read(), 0xC1);
+        assert_eq!(dma.tick(), None);
+        assert!(!dma.is_conflicting(0xFE00));
+        assert_eq!(dma.tick(), Some((0xC100, 0)));
+        assert!(dma.is_conflicting(0xFE00));
+    }
+
+    #[test]
+    fn copies_the_whole_oam() {
+        let mut dma = Dma::new(0);
+        dma.write(0xC1);
+        let copied = run(&mut dma, Dma::LENGTH + 1);
+        for index in 0..Dma::LENGTH {
+            assert_eq!(copied[index + 1], Some((0xC100 + index as u16, index)));
+        }
+        assert!(dma.is_conflicting(0xFE00));
+        assert_eq!(dma.tick(), None);
+        assert!(!dma.is_conflicting(0xFE00));
+    }
+
+    #[test]
+    fn restarts_after_the_setup_cycle() {
+        let mut dma = Dma::new(0);
+        dma.write(0xC1);
+        run(&mut dma, 11);
+        dma.write(0xD0);
+        // the running transfer goes on during the setup of the new one
+        assert_eq!(dma.tick(), Some((0xC10A, 10)));
+        assert!(dma.is_conflicting(0xFE00));
+        assert_eq!(dma.tick(), Some((0xD000, 0)));
+        assert_eq!(dma.tick(), Some((0xD001, 1)));
+    }
+
+    #[test]
+    fn reads_echo_ram_from_wram() {
+        let mut dma = Dma::new(0);
+        dma.write(0xF1);
+        assert_eq!(run(&mut dma, 2)[1], Some((0xD100, 0)));
+    }
+
+    #[test]
+    fn conflicts_on_the_bus_it_reads_from() {
+        let mut dma = Dma::new(0);
+        dma.write(0xC1);
+        run(&mut dma, 3);
+        assert_eq!(dma.current_source(), Some(0xC101));
+        for &(address, conflicting) in &[
+            (0x0150, true),
+            (0x4000, true),
+            (0x8000, false),
+            (0x9FFF, false),
+            (0xA000, true),
+            (0xC100, true),
+            (0xE000, true),
+            (0xFE00, true),
+            (0xFF0F, false),
+            (0xFF80, false),
+        ] {
+            assert_eq!(dma.is_conflicting(address), conflicting, "{:#06x}", address);
+        }
+
+        let mut dma = Dma::new(0);
+        dma.write(0x80);
+        run(&mut dma, 2);
+        assert_eq!(dma.current_source(), Some(0x8000));
+        for &(address, conflicting) in &[
+            (0x0150, false),
+            (0x8000, true),
+            (0x9FFF, true),
+            (0xC100, false),
+            (0xFE00, true),
+            (0xFF80, false),
+        ] {
+            assert_eq!(dma.is_conflicting(address), conflicting, "{:#06x}", address);
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// P1 register. The game selects the rows to read with bits 4-5 (active low),
+// the state of the selected keys is ANDed on the 4 input lines (0 = pressed)
+pub struct Joypad {
+    select: u8,
+    directions: u8,
+    buttons: u8,
+}
+
+impl Joypad {
+    pub const REGISTER: u16 = 0xFF00;
+
+    const SELECT_DIRECTIONS: u8 = 0b10000;
+    const SELECT_BUTTONS: u8 = 0b100000;
+    const SELECT_BITS: u8 = 0b110000;
+    const UNUSED_BITS: u8 = 0b11000000;
+
+    pub fn new() -> Self {
+        Joypad {
+            select: Joypad::SELECT_BITS,
+            directions: 0xF,
+            buttons: 0xF,
+        }
+    }
+
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0xF;
+        if self.select & Joypad::SELECT_DIRECTIONS == 0 {
+            lines &= self.directions;
+        }
+        if self.select & Joypad::SELECT_BUTTONS == 0 {
+            lines &= self.buttons;
+        }
+        lines
+    }
+
+    // True when one of the selected keys is held down
+    pub fn is_pressed(&self) -> bool {
+        self.input_lines() != 0xF
+    }
+
+    pub fn read(&self) -> u8 {
+        Joypad::UNUSED_BITS | self.select | self.input_lines()
+    }
+
+    // Only the select bits can be written, returns true when the joypad interrupt is requested
+    pub fn write(&mut self, data: u8) -> bool {
+        let before = self.input_lines();
+        self.select = data & Joypad::SELECT_BITS;
+        Joypad::is_falling_edge(before, self.input_lines())
+    }
+
+    // Update the keys state, one bit per key with 0 meaning pressed.
+    // Returns true when the joypad interrupt is requested
+    pub fn set_keys(&mut self, directions: u8, buttons: u8) -> bool {
+        let before = self.input_lines();
+        self.directions = directions & 0xF;
+        self.buttons = buttons & 0xF;
+        Joypad::is_falling_edge(before, self.input_lines())
+    }
+
+    // the interrupt fires when any input line goes from high to low
+    fn is_falling_edge(before: u8, after: u8) -> bool {
+        before & !after != 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RIGHT: u8 = 0b1110;
+    const START: u8 = 0b0111;
+
+    #[test]
+    fn reads_selected_rows() {
+        let mut joypad = Joypad::new();
+        joypad.set_keys(RIGHT, START);
+        // select bit, keys read, the unused bits read as 1
+        for &(select, lines) in &[
+            (0b110000, 0xF),
+            (0b100000, RIGHT),
+            (0b010000, START),
+            (0b000000, RIGHT & START),
+        ] {
+            joypad.write(select | 0xF);
+            assert_eq!(joypad.read(), 0b11000000 | select | lines);
+        }
+    }
+
+    #[test]
+    fn presses_only_selected_keys() {
+        let mut joypad = Joypad::new();
+        // the directions are deselected, only the buttons count
+        joypad.write(Joypad::SELECT_DIRECTIONS);
+        joypad.set_keys(RIGHT, 0xF);
+        assert!(!joypad.is_pressed());
+        joypad.set_keys(RIGHT, START);
+        assert!(joypad.is_pressed());
+    }
+
+    #[test]
+    fn requests_interrupt_when_a_line_goes_low() {
+        let mut joypad = Joypad::new();
+        joypad.write(Joypad::SELECT_BUTTONS);
+        assert!(joypad.set_keys(RIGHT, 0xF));
+        // already low, no new edge
+        assert!(!joypad.set_keys(RIGHT, 0xF));
+        // another line going low is a new edge
+        assert!(joypad.set_keys(0b1100, 0xF));
+        assert!(!joypad.set_keys(0xF, 0xF));
+
+        // a key of the deselected row
+        assert!(!joypad.set_keys(0xF, START));
+        // selecting the row of a held key pulls its line low too
+        assert!(joypad.write(Joypad::SELECT_DIRECTIONS));
+        assert!(!joypad.write(0));
+        assert!(!joypad.write(Joypad::SELECT_BITS));
+    }
+}
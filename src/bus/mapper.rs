@@ -0,0 +1,135 @@
+use super::cartridge::*;
+use super::mbc1::MBC1;
+use super::mbc2::MBC2;
+use super::mbc3::MBC3;
+use super::mbc5::MBC5;
+
+// Memory bank controller of a cartridge: it decides which part of the ROM is
+// visible in 0x0000-0x7FFF and owns whatever sits at 0xA000-0xBFFF
+pub trait Mapper {
+    // Offset in the ROM image of the byte seen at address (0x0000-0x7FFF)
+    fn rom_offset(&self, address: u16) -> usize;
+    // Writes to the ROM area go to the controller registers
+    fn write_register(&mut self, address: u16, data: u8);
+    fn read_ram(&self, address: u16) -> u8;
+    // Returns true when the write changed the content of the RAM
+    fn write_ram(&mut self, address: u16, data: u8) -> bool;
+
+    // Advance the cartridge hardware by a number of clock cycles
+    fn tick(&mut self, _cycles: u32) {}
+
+    // State of the rumble motor, for cartridges that have one
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    // Battery backed state of the cartridge, as stored in save files
+    fn save_data(&self) -> Vec<u8> {
+        Vec::new()
+    }
+
+    fn load_save_data(&mut self, _data: &[u8]) {}
+
+    // Advance the clock of the loaded save by the real time since it was written
+    fn catch_up_clock(&mut self) {}
+}
+
+pub fn create_mapper(
+    header: &CartridgeHeader,
+    rom: &[u8],
+) -> Result<Box<dyn Mapper>, CartridgeError> {
+    match header.cartridge_type.controller {
+        Controller::RomOnly => Ok(Box::new(NoMapper::new(header))),
+        Controller::MBC1 => Ok(Box::new(MBC1::new(header, rom))),
+        Controller::MBC2 => Ok(Box::new(MBC2::new())),
+        Controller::MBC3 => Ok(Box::new(MBC3::new(header))),
+        Controller::MBC5 => Ok(Box::new(MBC5::new(header))),
+        controller => Err(CartridgeError::UnsupportedController(controller)),
+    }
+}
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+// External RAM split in 8 KiB banks, reads as 0xFF when absent
+pub struct CartridgeRam {
+    data: Vec<u8>,
+}
+
+impl CartridgeRam {
+    pub fn new(size: usize) -> Self {
+        CartridgeRam {
+            data: vec![0; size],
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Restore the RAM content, anything beyond its size is ignored
+    pub fn load(&mut self, data: &[u8]) {
+        let size = self.data.len().min(data.len());
+        self.data[..size].copy_from_slice(&data[..size]);
+    }
+
+    fn offset(&self, bank: usize, address: u16) -> usize {
+        // smaller RAM chips are mirrored over the whole bank
+        (bank * RAM_BANK_SIZE + (address as usize & (RAM_BANK_SIZE - 1))) % self.data.len()
+    }
+
+    pub fn read(&self, bank: usize, address: u16) -> u8 {
+        if self.data.is_empty() {
+            return 0xFF;
+        }
+        self.data[self.offset(bank, address)]
+    }
+
+    // Returns true when the stored byte changed
+    pub fn write(&mut self, bank: usize, address: u16, data: u8) -> bool {
+        if self.data.is_empty() {
+            return false;
+        }
+        let offset = self.offset(bank, address);
+        let changed = self.data[offset] != data;
+        self.data[offset] = data;
+        changed
+    }
+}
+
+// 32 KiB cartridges without controller, optionally with up to 8 KiB of RAM
+pub struct NoMapper {
+    ram: CartridgeRam,
+}
+
+impl NoMapper {
+    pub fn new(header: &CartridgeHeader) -> Self {
+        NoMapper {
+            ram: CartridgeRam::new(header.ram_size),
+        }
+    }
+}
+
+impl Mapper for NoMapper {
+    fn rom_offset(&self, address: u16) -> usize {
+        address as usize
+    }
+
+    fn write_register(&mut self, _address: u16, _data: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram.read(0, address)
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        self.ram.write(0, address, data)
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.data().to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.ram.load(data);
+    }
+}
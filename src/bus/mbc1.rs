@@ -0,0 +1,208 @@
+use super::cartridge::*;
+use super::mapper::*;
+
+// MBC1, up to 2 MiB of ROM and 32 KiB of RAM. The 2 bits register is either
+// the upper bits of the ROM bank or the RAM bank, depending on the mode
+pub struct MBC1 {
+    ram: CartridgeRam,
+    ram_enabled: bool,
+    bank1: u8, // 5 bits, lower ROM bank bits
+    bank2: u8, // 2 bits
+    advanced_mode: bool,
+    multicart: bool,
+}
+
+impl MBC1 {
+    const NINTENDO_LOGO: usize = 0x104;
+    const NINTENDO_LOGO_END: usize = 0x134;
+    const MULTICART_SIZE: usize = 0x100000;
+
+    pub fn new(header: &CartridgeHeader, rom: &[u8]) -> Self {
+        MBC1 {
+            ram: CartridgeRam::new(header.ram_size),
+            ram_enabled: false,
+            bank1: 1,
+            bank2: 0,
+            advanced_mode: false,
+            multicart: MBC1::is_multicart(rom),
+        }
+    }
+
+    // MBC1M multicarts wire bank2 to ROM bits 4-5 instead of 5-6. Each game of
+    // the compilation has its own header, so look for a second Nintendo logo
+    // at the start of bank 0x10
+    fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != MBC1::MULTICART_SIZE {
+            return false;
+        }
+
+        let logo = &rom[MBC1::NINTENDO_LOGO..MBC1::NINTENDO_LOGO_END];
+        let second_game = 0x10 * ROM_BANK_SIZE;
+        logo == &rom[second_game + MBC1::NINTENDO_LOGO..second_game + MBC1::NINTENDO_LOGO_END]
+    }
+
+    fn bank2_shift(&self) -> u8 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn bank1_mask(&self) -> u8 {
+        if self.multicart {
+            0b1111
+        } else {
+            0b11111
+        }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.advanced_mode {
+            self.bank2 as usize
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for MBC1 {
+    fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            // bank 0 area can only be remapped in advanced mode
+            0x0000..=0x3FFF if self.advanced_mode => self.bank2 << self.bank2_shift(),
+            0x0000..=0x3FFF => 0,
+            _ => (self.bank2 << self.bank2_shift()) | (self.bank1 & self.bank1_mask()),
+        } as usize;
+
+        bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            // bank 0 can't be selected here, checked on all 5 bits
+            0x2000..=0x3FFF => self.bank1 = (data & 0b11111).max(1),
+            0x4000..=0x5FFF => self.bank2 = data & 0b11,
+            0x6000..=0x7FFF => self.advanced_mode = data & 0b1 != 0,
+            _ => panic!("Address {:#06x} is not a MBC1 register", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram.read(self.ram_bank(), address)
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        self.ram_enabled && self.ram.write(self.ram_bank(), address, data)
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.data().to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(mbc: &MBC1, address: u16) -> usize {
+        mbc.rom_offset(address) / ROM_BANK_SIZE
+    }
+
+    #[test]
+    fn selects_rom_bank() {
+        let rom = vec![0; 0x200000];
+        let mut mbc = MBC1::new(&test_header(0x03, rom.len(), 0), &rom);
+        assert_eq!((bank(&mbc, 0x0000), bank(&mbc, 0x4000)), (0, 1));
+
+        mbc.write_register(0x2000, 0x05);
+        assert_eq!(bank(&mbc, 0x7FFF), 5);
+        assert_eq!(mbc.rom_offset(0x4123), 5 * ROM_BANK_SIZE + 0x123);
+
+        // bank 0 reads as 1, and so do its multiples on the upper bits
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(bank(&mbc, 0x4000), 1);
+        mbc.write_register(0x2000, 0xE0);
+        mbc.write_register(0x4000, 0x01);
+        assert_eq!(bank(&mbc, 0x4000), 0x21);
+
+        mbc.write_register(0x4000, 0x03);
+        mbc.write_register(0x2000, 0x1F);
+        assert_eq!(bank(&mbc, 0x4000), 0x7F);
+        assert_eq!(bank(&mbc, 0x0000), 0);
+    }
+
+    #[test]
+    fn remaps_bank_0_area_in_advanced_mode() {
+        let rom = vec![0; 0x200000];
+        let mut mbc = MBC1::new(&test_header(0x03, rom.len(), 0), &rom);
+        mbc.write_register(0x4000, 0x02);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!((bank(&mbc, 0x0000), bank(&mbc, 0x4000)), (0x40, 0x41));
+
+        mbc.write_register(0x6000, 0x00);
+        assert_eq!(bank(&mbc, 0x0000), 0);
+    }
+
+    #[test]
+    fn selects_ram_bank_in_advanced_mode() {
+        let rom = vec![0; 0x8000];
+        let mut mbc = MBC1::new(&test_header(0x03, rom.len(), 0x8000), &rom);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        assert!(!mbc.write_ram(0xA000, 0x12));
+
+        mbc.write_register(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        assert!(mbc.write_ram(0xA000, 0x12));
+        assert!(!mbc.write_ram(0xA000, 0x12));
+
+        // the bank is ignored in simple mode
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        assert!(mbc.write_ram(0xA000, 0x34));
+        mbc.write_register(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0x12);
+        mbc.write_register(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xA000), 0x34);
+
+        mbc.write_register(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn detects_multicart() {
+        let mut rom = vec![0; MBC1::MULTICART_SIZE];
+        for (i, byte) in rom[MBC1::NINTENDO_LOGO..MBC1::NINTENDO_LOGO_END]
+            .iter_mut()
+            .enumerate()
+        {
+            *byte = i as u8 + 1;
+        }
+        let mut mbc = MBC1::new(&test_header(0x03, rom.len(), 0), &rom);
+        assert!(!mbc.multicart);
+
+        let logo = rom[MBC1::NINTENDO_LOGO..MBC1::NINTENDO_LOGO_END].to_vec();
+        let second_game = 0x10 * ROM_BANK_SIZE;
+        rom[second_game + MBC1::NINTENDO_LOGO..second_game + MBC1::NINTENDO_LOGO_END]
+            .copy_from_slice(&logo);
+        mbc = MBC1::new(&test_header(0x03, rom.len(), 0), &rom);
+        assert!(mbc.multicart);
+
+        // bank2 moves to bits 4-5 and bank1 loses its top bit
+        mbc.write_register(0x4000, 0x01);
+        mbc.write_register(0x2000, 0x13);
+        assert_eq!(bank(&mbc, 0x4000), 0x13);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(bank(&mbc, 0x0000), 0x10);
+    }
+}
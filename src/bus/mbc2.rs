@@ -0,0 +1,135 @@
+use super::mapper::*;
+
+// MBC2, up to 256 KiB of ROM and a built-in RAM of 512 half bytes.
+// Address bit 8 tells the RAM enable register from the ROM bank one
+pub struct MBC2 {
+    ram: [u8; MBC2::RAM_SIZE],
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl MBC2 {
+    const RAM_SIZE: usize = 512;
+    const REGISTER_SELECT: u16 = 0x100;
+
+    pub fn new() -> Self {
+        MBC2 {
+            ram: [0; MBC2::RAM_SIZE],
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mapper for MBC2 {
+    fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+
+        bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x3FFF if address & MBC2::REGISTER_SELECT == 0 => {
+                self.ram_enabled = data & 0x0F == 0x0A
+            }
+            0x0000..=0x3FFF => self.rom_bank = (data & 0x0F).max(1),
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        // only the lower nibble is stored, the 512 entries repeat over the whole area
+        self.ram[address as usize % MBC2::RAM_SIZE] | 0xF0
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        let nibble = &mut self.ram[address as usize % MBC2::RAM_SIZE];
+        let changed = *nibble != data & 0x0F;
+        *nibble = data & 0x0F;
+        changed
+    }
+
+    // One byte per half byte of RAM, the way most emulators store it
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (cell, byte) in self.ram.iter_mut().zip(data) {
+            *cell = byte & 0x0F;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn enabled_mbc() -> MBC2 {
+        let mut mbc = MBC2::new();
+        mbc.write_register(0x0000, 0x0A);
+        mbc
+    }
+
+    #[test]
+    fn selects_register_with_address_bit_8() {
+        let mut mbc = MBC2::new();
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+
+        // bit 8 set, the ROM bank is written whatever the address range
+        mbc.write_register(0x0100, 0x0A);
+        assert_eq!(mbc.rom_offset(0x4000), 0x0A * ROM_BANK_SIZE);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        mbc.write_register(0x3F00, 0x15);
+        assert_eq!(mbc.rom_offset(0x4123), 0x05 * ROM_BANK_SIZE + 0x123);
+        mbc.write_register(0x2100, 0x00);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+        assert_eq!(mbc.rom_offset(0x0123), 0x123);
+
+        // bit 8 cleared, RAM gets enabled instead
+        mbc.write_register(0x2000, 0x0A);
+        assert_eq!(mbc.rom_offset(0x4000), ROM_BANK_SIZE);
+        assert_eq!(mbc.read_ram(0xA000), 0xF0);
+        mbc.write_register(0x3E00, 0x00);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn stores_half_bytes() {
+        let mut mbc = enabled_mbc();
+        assert!(mbc.write_ram(0xA000, 0xAB));
+        assert_eq!(mbc.read_ram(0xA000), 0xFB);
+        // the upper nibble is not stored, so nothing changed
+        assert!(!mbc.write_ram(0xA000, 0x5B));
+        assert_eq!(mbc.save_data()[0], 0x0B);
+    }
+
+    #[test]
+    fn mirrors_ram_over_the_whole_area() {
+        let mut mbc = enabled_mbc();
+        mbc.write_ram(0xA1FF, 0x07);
+        for &address in &[0xA3FF, 0xB1FF, 0xBFFF] {
+            assert_eq!(mbc.read_ram(address), 0xF7);
+        }
+        mbc.write_ram(0xBE00, 0x03);
+        assert_eq!(mbc.read_ram(0xA000), 0xF3);
+    }
+
+    #[test]
+    fn ignores_ram_accesses_while_disabled() {
+        let mut mbc = MBC2::new();
+        assert!(!mbc.write_ram(0xA000, 0x01));
+        mbc.write_register(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0xA000), 0xF0);
+    }
+}
@@ -0,0 +1,478 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::cartridge::*;
+use super::mapper::*;
+
+// Clock counter of the MBC3, advanced by emulated cycles so runs are reproducible
+#[derive(Clone, Copy, Default)]
+struct ClockRegisters {
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, // 9 bits
+    halted: bool,
+    day_carry: bool,
+}
+
+impl ClockRegisters {
+    // Registers keep counting up to their bit width when set to an invalid value
+    fn advance_second(&mut self) {
+        self.seconds = (self.seconds + 1) & 0x3F;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3F;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1F;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days += 1;
+        if self.days > 0x1FF {
+            self.days = 0;
+            self.day_carry = true;
+        }
+    }
+
+    fn is_valid(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    // Move forward by many seconds at once, e.g. the time spent with the emulator off
+    fn advance(&mut self, mut seconds: u64) {
+        // out of range registers first count up to their wrap around
+        while seconds > 0 && !self.is_valid() {
+            self.advance_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+
+        let total = self.seconds as u64
+            + self.minutes as u64 * 60
+            + self.hours as u64 * 3600
+            + self.days as u64 * 86400
+            + seconds;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days > 0x1FF {
+            self.day_carry = true;
+        }
+        self.days = (days & 0x1FF) as u16;
+    }
+
+    fn day_high(&self) -> u8 {
+        ((self.days >> 8) as u8 & 0b1)
+            | if self.halted { 0b1000000 } else { 0 }
+            | if self.day_carry { 0b10000000 } else { 0 }
+    }
+
+    fn read(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.seconds,
+            0x09 => self.minutes,
+            0x0A => self.hours,
+            0x0B => (self.days & 0xFF) as u8,
+            0x0C => self.day_high(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write(&mut self, register: u8, data: u8) {
+        match register {
+            0x08 => self.seconds = data & 0x3F,
+            0x09 => self.minutes = data & 0x3F,
+            0x0A => self.hours = data & 0x1F,
+            0x0B => self.days = (self.days & 0x100) | data as u16,
+            0x0C => {
+                self.days = (self.days & 0xFF) | (((data & 0b1) as u16) << 8);
+                self.halted = data & 0b1000000 != 0;
+                self.day_carry = data & 0b10000000 != 0;
+            }
+            _ => {}
+        }
+    }
+
+    // Each register as a 32 bits little endian value
+    fn write_save(&self, save: &mut Vec<u8>) {
+        for register in 0x08..=0x0C {
+            save.extend_from_slice(&(self.read(register) as u32).to_le_bytes());
+        }
+    }
+
+    fn read_save(data: &[u8]) -> Self {
+        let mut registers = ClockRegisters::default();
+        for (i, register) in (0x08..=0x0C).enumerate() {
+            registers.write(register, data[i * 4]);
+        }
+        registers
+    }
+}
+
+struct RealTimeClock {
+    current: ClockRegisters,
+    latched: ClockRegisters,
+    cycles: u32, // sub-second counter
+    latch_armed: bool,
+    saved_at: Option<u64>, // timestamp of the loaded save, until the clock caught up
+}
+
+impl RealTimeClock {
+    const CYCLES_PER_SECOND: u32 = 4_194_304;
+    // 5 current registers, 5 latched ones and a 64 bits timestamp
+    const SAVE_SIZE: usize = 48;
+
+    fn new() -> Self {
+        RealTimeClock {
+            current: ClockRegisters::default(),
+            latched: ClockRegisters::default(),
+            cycles: 0,
+            latch_armed: false,
+            saved_at: None,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.current.halted {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= RealTimeClock::CYCLES_PER_SECOND {
+            self.cycles -= RealTimeClock::CYCLES_PER_SECOND;
+            self.current.advance_second();
+        }
+    }
+
+    // Writing 0 then 1 copies the running clock to the readable registers
+    fn write_latch(&mut self, data: u8) {
+        if self.latch_armed && data == 1 {
+            self.latched = self.current;
+        }
+        self.latch_armed = data == 0;
+    }
+
+    fn write(&mut self, register: u8, data: u8) {
+        if register == 0x08 {
+            // writing seconds resets the sub-second divider
+            self.cycles = 0;
+        }
+        self.current.write(register, data);
+        self.latched.write(register, data);
+    }
+
+    fn to_save(&self) -> Vec<u8> {
+        let mut save = Vec::with_capacity(RealTimeClock::SAVE_SIZE);
+        self.current.write_save(&mut save);
+        self.latched.write_save(&mut save);
+        save.extend_from_slice(&RealTimeClock::now().to_le_bytes());
+        save
+    }
+
+    fn load_save(&mut self, data: &[u8]) {
+        self.current = ClockRegisters::read_save(&data[0..20]);
+        self.latched = ClockRegisters::read_save(&data[20..40]);
+
+        let mut timestamp = [0; 8];
+        timestamp.copy_from_slice(&data[40..48]);
+        // a zero timestamp means it was never set
+        self.saved_at = match u64::from_le_bytes(timestamp) {
+            0 => None,
+            timestamp => Some(timestamp),
+        };
+    }
+
+    // The clock keeps running while the emulator is off. A timestamp from
+    // the future doesn't move it back
+    fn catch_up(&mut self) {
+        if let Some(saved_at) = self.saved_at.take() {
+            if !self.current.halted {
+                self.current
+                    .advance(RealTimeClock::now().saturating_sub(saved_at));
+            }
+        }
+    }
+
+    fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_secs())
+            .unwrap_or(0)
+    }
+}
+
+// MBC3, up to 2 MiB of ROM (4 MiB on MBC30), 32 KiB of RAM and an optional
+// real time clock whose registers are selected in place of a RAM bank
+pub struct MBC3 {
+    ram: CartridgeRam,
+    rtc: Option<RealTimeClock>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    rom_bank_mask: u8,
+    ram_bank: u8, // 0x08-0x0C select a clock register
+}
+
+impl MBC3 {
+    pub fn new(header: &CartridgeHeader) -> Self {
+        MBC3 {
+            ram: CartridgeRam::new(header.ram_size),
+            rtc: if header.cartridge_type.timer {
+                Some(RealTimeClock::new())
+            } else {
+                None
+            },
+            ram_enabled: false,
+            rom_bank: 1,
+            // MBC30 has an extra ROM bank bit
+            rom_bank_mask: if header.rom_size > 0x200000 {
+                0xFF
+            } else {
+                0x7F
+            },
+            ram_bank: 0,
+        }
+    }
+}
+
+impl Mapper for MBC3 {
+    fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+
+        bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x1FFF => self.ram_enabled = data & 0x0F == 0x0A,
+            0x2000..=0x3FFF => self.rom_bank = (data & self.rom_bank_mask).max(1),
+            0x4000..=0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000..=0x7FFF => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(data);
+                }
+            }
+            _ => panic!("Address {:#06x} is not a MBC3 register", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        match (self.ram_bank, &self.rtc) {
+            (0x00..=0x07, _) => self.ram.read(self.ram_bank as usize, address),
+            (0x08..=0x0C, Some(rtc)) => rtc.latched.read(self.ram_bank),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match (self.ram_bank, &mut self.rtc) {
+            (0x00..=0x07, _) => self.ram.write(self.ram_bank as usize, address, data),
+            // the clock is saved along the RAM but doesn't call for a save on its own
+            (0x08..=0x0C, Some(rtc)) => {
+                rtc.write(self.ram_bank, data);
+                false
+            }
+            _ => false,
+        }
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    // RAM content followed by the clock state, in the format shared with other emulators
+    fn save_data(&self) -> Vec<u8> {
+        let mut save = self.ram.data().to_vec();
+        if let Some(rtc) = &self.rtc {
+            save.extend(rtc.to_save());
+        }
+        save
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram_size = self.ram.data().len();
+        self.ram.load(data);
+        if let Some(rtc) = &mut self.rtc {
+            if data.len() >= ram_size + RealTimeClock::SAVE_SIZE {
+                rtc.load_save(&data[ram_size..ram_size + RealTimeClock::SAVE_SIZE]);
+            }
+        }
+    }
+
+    fn catch_up_clock(&mut self) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.catch_up();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(mbc: &MBC3, address: u16) -> usize {
+        mbc.rom_offset(address) / ROM_BANK_SIZE
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_register(0x6000, 0x00);
+        mbc.write_register(0x6000, 0x01);
+    }
+
+    fn read_clock(mbc: &mut MBC3, register: u8) -> u8 {
+        mbc.write_register(0x4000, register);
+        mbc.read_ram(0xA000)
+    }
+
+    #[test]
+    fn selects_rom_bank() {
+        let mut mbc = MBC3::new(&test_header(0x13, 0x200000, 0x8000));
+        assert_eq!((bank(&mbc, 0x0000), bank(&mbc, 0x4000)), (0, 1));
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(bank(&mbc, 0x4000), 1);
+        mbc.write_register(0x2000, 0x45);
+        assert_eq!(bank(&mbc, 0x7FFF), 0x45);
+        mbc.write_register(0x2000, 0xFF);
+        assert_eq!(bank(&mbc, 0x4000), 0x7F);
+
+        // MBC30
+        let mut mbc = MBC3::new(&test_header(0x13, 0x400000, 0x10000));
+        mbc.write_register(0x2000, 0xFF);
+        assert_eq!(bank(&mbc, 0x4000), 0xFF);
+    }
+
+    #[test]
+    fn selects_ram_bank() {
+        let mut mbc = MBC3::new(&test_header(0x13, 0x200000, 0x8000));
+        assert!(!mbc.write_ram(0xA000, 0x12));
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x03);
+        assert!(mbc.write_ram(0xA001, 0x12));
+        assert_eq!(mbc.read_ram(0xA001), 0x12);
+        assert_eq!(mbc.save_data()[3 * RAM_BANK_SIZE + 1], 0x12);
+        mbc.write_register(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xA001), 0x00);
+
+        // no clock on this cartridge
+        mbc.write_register(0x4000, 0x08);
+        assert!(!mbc.write_ram(0xA000, 0x12));
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+    }
+
+    #[test]
+    fn latches_clock() {
+        let mut mbc = MBC3::new(&test_header(0x10, 0x200000, 0x8000));
+        mbc.write_register(0x0000, 0x0A);
+        mbc.tick(RealTimeClock::CYCLES_PER_SECOND * 61);
+        assert_eq!(read_clock(&mut mbc, 0x08), 0);
+
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x08), 1);
+        assert_eq!(read_clock(&mut mbc, 0x09), 1);
+
+        // the latched registers don't follow the clock until latched again
+        mbc.tick(RealTimeClock::CYCLES_PER_SECOND);
+        assert_eq!(read_clock(&mut mbc, 0x08), 1);
+        mbc.write_register(0x6000, 0x01);
+        assert_eq!(read_clock(&mut mbc, 0x08), 1);
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x08), 2);
+    }
+
+    #[test]
+    fn writes_clock_registers() {
+        let mut mbc = MBC3::new(&test_header(0x10, 0x200000, 0x8000));
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x0A);
+        assert!(!mbc.write_ram(0xA000, 23));
+        mbc.write_register(0x4000, 0x09);
+        mbc.write_ram(0xA000, 59);
+        mbc.write_register(0x4000, 0x08);
+        mbc.write_ram(0xA000, 59);
+        mbc.write_register(0x4000, 0x0B);
+        mbc.write_ram(0xA000, 0xFF);
+        mbc.write_register(0x4000, 0x0C);
+        mbc.write_ram(0xA000, 0x01);
+
+        mbc.tick(RealTimeClock::CYCLES_PER_SECOND);
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x08), 0);
+        assert_eq!(read_clock(&mut mbc, 0x0A), 0);
+        assert_eq!(read_clock(&mut mbc, 0x0B), 0);
+        assert_eq!(read_clock(&mut mbc, 0x0C), 0b10000000);
+
+        // halted clocks don't count
+        mbc.write_ram(0xA000, 0b1000000);
+        mbc.tick(RealTimeClock::CYCLES_PER_SECOND);
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x08), 0);
+    }
+
+    #[test]
+    fn advances_by_many_seconds() {
+        let mut clock = ClockRegisters::default();
+        clock.advance(3 * 86400 + 2 * 3600 + 60 + 5);
+        assert_eq!(
+            (clock.days, clock.hours, clock.minutes, clock.seconds),
+            (3, 2, 1, 5)
+        );
+        assert!(!clock.day_carry);
+
+        clock.advance(0x200 * 86400);
+        assert_eq!(clock.days, 3);
+        assert!(clock.day_carry);
+
+        // invalid values count up to their bit width first
+        let mut clock = ClockRegisters {
+            seconds: 62,
+            ..ClockRegisters::default()
+        };
+        clock.advance(2);
+        assert_eq!((clock.minutes, clock.seconds), (0, 0));
+        clock.advance(60);
+        assert_eq!((clock.minutes, clock.seconds), (1, 0));
+    }
+
+    #[test]
+    fn catches_up_with_saves() {
+        let mut mbc = MBC3::new(&test_header(0x10, 0x200000, 0x8000));
+        let mut save = mbc.save_data();
+        let saved_at = RealTimeClock::now() - 3600;
+        let timestamp = save.len() - 8;
+        save[timestamp..].copy_from_slice(&saved_at.to_le_bytes());
+        mbc.load_save_data(&save);
+        mbc.catch_up_clock();
+        mbc.write_register(0x0000, 0x0A);
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x0A), 1);
+
+        // a timestamp from the future leaves the clock alone
+        let mut mbc = MBC3::new(&test_header(0x10, 0x200000, 0x8000));
+        save[timestamp..].copy_from_slice(&(saved_at + 7200).to_le_bytes());
+        mbc.load_save_data(&save);
+        mbc.catch_up_clock();
+        mbc.write_register(0x0000, 0x0A);
+        latch(&mut mbc);
+        assert_eq!(read_clock(&mut mbc, 0x0A), 0);
+        assert_eq!(read_clock(&mut mbc, 0x08), 0);
+    }
+}
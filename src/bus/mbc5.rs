@@ -0,0 +1,151 @@
+use super::cartridge::*;
+use super::mapper::*;
+
+// MBC5, up to 8 MiB of ROM through a 9 bits bank number and 128 KiB of RAM.
+// On rumble cartridges, bit 3 of the RAM bank register drives the motor
+pub struct MBC5 {
+    ram: CartridgeRam,
+    ram_enabled: bool,
+    rom_bank: u16, // 9 bits, bank 0 can be mapped at 0x4000 too
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble: bool,
+}
+
+impl MBC5 {
+    const RUMBLE_MOTOR: u8 = 0b1000;
+
+    pub fn new(header: &CartridgeHeader) -> Self {
+        MBC5 {
+            ram: CartridgeRam::new(header.ram_size),
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble: header.cartridge_type.rumble,
+            rumble: false,
+        }
+    }
+}
+
+impl Mapper for MBC5 {
+    fn rom_offset(&self, address: u16) -> usize {
+        let bank = match address {
+            0x0000..=0x3FFF => 0,
+            _ => self.rom_bank as usize,
+        };
+
+        bank * ROM_BANK_SIZE + (address as usize & (ROM_BANK_SIZE - 1))
+    }
+
+    fn write_register(&mut self, address: u16, data: u8) {
+        match address {
+            // all 8 bits are decoded, unlike the older controllers 0x1A disables RAM
+            0x0000..=0x1FFF => self.ram_enabled = data == 0x0A,
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | data as u16,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((data as u16 & 0b1) << 8),
+            0x4000..=0x5FFF if self.has_rumble => {
+                self.rumble = data & MBC5::RUMBLE_MOTOR != 0;
+                self.ram_bank = data & 0b111;
+            }
+            0x4000..=0x5FFF => self.ram_bank = data & 0x0F,
+            0x6000..=0x7FFF => {}
+            _ => panic!("Address {:#06x} is not a MBC5 register", address),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xFF;
+        }
+        self.ram.read(self.ram_bank as usize, address)
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) -> bool {
+        self.ram_enabled && self.ram.write(self.ram_bank as usize, address, data)
+    }
+
+    fn rumble(&self) -> bool {
+        self.rumble
+    }
+
+    fn save_data(&self) -> Vec<u8> {
+        self.ram.data().to_vec()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        self.ram.load(data);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bank(mbc: &MBC5, address: u16) -> usize {
+        mbc.rom_offset(address) / ROM_BANK_SIZE
+    }
+
+    #[test]
+    fn selects_9_bits_rom_bank() {
+        let mut mbc = MBC5::new(&test_header(0x19, 0x800000, 0));
+        assert_eq!((bank(&mbc, 0x0000), bank(&mbc, 0x4000)), (0, 1));
+
+        // unlike the other controllers, bank 0 can be mapped at 0x4000
+        mbc.write_register(0x2000, 0x00);
+        assert_eq!(bank(&mbc, 0x4000), 0);
+
+        mbc.write_register(0x2FFF, 0x42);
+        mbc.write_register(0x3000, 0x01);
+        assert_eq!(bank(&mbc, 0x4000), 0x142);
+        assert_eq!(mbc.rom_offset(0x7123), 0x142 * ROM_BANK_SIZE + 0x3123);
+        mbc.write_register(0x2000, 0xFF);
+        assert_eq!(bank(&mbc, 0x4000), 0x1FF);
+
+        // only the lowest bit of the upper register is used
+        mbc.write_register(0x3FFF, 0xFE);
+        assert_eq!(bank(&mbc, 0x4000), 0x0FF);
+        assert_eq!(bank(&mbc, 0x3FFF), 0);
+    }
+
+    #[test]
+    fn selects_ram_bank() {
+        let mut mbc = MBC5::new(&test_header(0x1B, 0x100000, 0x20000));
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x09);
+        assert!(mbc.write_ram(0xA000, 0x99));
+        mbc.write_register(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+        mbc.write_register(0x4000, 0x09);
+        assert_eq!(mbc.read_ram(0xA000), 0x99);
+        assert!(!mbc.rumble());
+    }
+
+    #[test]
+    fn masks_rumble_motor_out_of_ram_bank() {
+        let mut mbc = MBC5::new(&test_header(0x1E, 0x100000, 0x20000));
+        mbc.write_register(0x0000, 0x0A);
+        mbc.write_register(0x4000, 0x01);
+        assert!(mbc.write_ram(0xA000, 0x11));
+        assert!(!mbc.rumble());
+
+        mbc.write_register(0x4000, 0x09);
+        assert!(mbc.rumble());
+        assert_eq!(mbc.read_ram(0xA000), 0x11);
+
+        mbc.write_register(0x4000, 0x00);
+        assert!(!mbc.rumble());
+        assert_eq!(mbc.read_ram(0xA000), 0x00);
+    }
+
+    #[test]
+    fn enables_ram_with_0x0a_only() {
+        let mut mbc = MBC5::new(&test_header(0x1B, 0x100000, 0x20000));
+        mbc.write_register(0x0000, 0x0A);
+        assert!(mbc.write_ram(0xA000, 0x42));
+        mbc.write_register(0x1FFF, 0x1A);
+        assert_eq!(mbc.read_ram(0xA000), 0xFF);
+        assert!(!mbc.write_ram(0xA000, 0x24));
+        mbc.write_register(0x0000, 0x0A);
+        assert_eq!(mbc.read_ram(0xA000), 0x42);
+    }
+}
@@ -0,0 +1,80 @@
+// Link port. Without a cable plugged in, a transfer driven by the internal
+// clock shifts out SB and shifts in 1s, one bit every 512 cycles
+pub struct Serial {
+    data: u8,
+    control: u8,
+    unused_bits: u8,
+    bits_left: u8,
+    cycles: u16,
+}
+
+impl Serial {
+    pub const DATA: u16 = 0xFF01;
+    pub const CONTROL: u16 = 0xFF02;
+
+    const TRANSFER_START: u8 = 0b10000000;
+    const INTERNAL_CLOCK: u8 = 0b1;
+    const CLOCK_SPEED: u8 = 0b10; // CGB only
+    const UNUSED_BITS: u8 = 0b1111110;
+    const CYCLES_PER_BIT: u16 = 512;
+
+    pub fn new(cgb: bool) -> Self {
+        Serial {
+            data: 0,
+            control: 0,
+            unused_bits: if cgb {
+                Serial::UNUSED_BITS & !Serial::CLOCK_SPEED
+            } else {
+                Serial::UNUSED_BITS
+            },
+            bits_left: 0,
+            cycles: 0,
+        }
+    }
+
+    // Advance by one clock cycle, returns true when the serial interrupt is requested
+    pub fn tick(&mut self) -> bool {
+        if self.bits_left == 0 {
+            return false;
+        }
+
+        self.cycles += 1;
+        if self.cycles < Serial::CYCLES_PER_BIT {
+            return false;
+        }
+        self.cycles = 0;
+        self.data = (self.data << 1) | 1;
+        self.bits_left -= 1;
+        if self.bits_left > 0 {
+            return false;
+        }
+        self.control &= !Serial::TRANSFER_START;
+
+        true
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            Serial::DATA => self.data,
+            Serial::CONTROL => self.control | self.unused_bits,
+            _ => panic!("Address {:#06x} is not a serial register", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            Serial::DATA => self.data = data,
+            Serial::CONTROL => {
+                self.control = data & !self.unused_bits;
+                let start = Serial::TRANSFER_START | Serial::INTERNAL_CLOCK;
+                if self.control & start == start {
+                    self.bits_left = 8;
+                    self.cycles = 0;
+                } else {
+                    self.bits_left = 0;
+                }
+            }
+            _ => panic!("Address {:#06x} is not a serial register", address),
+        }
+    }
+}
@@ -0,0 +1,230 @@
+// DIV, TIMA, TMA and TAC. Everything is driven by a 16 bits divider counting
+// clock cycles, TIMA is incremented on a falling edge of one of its bits
+pub struct Timer {
+    divider: u16, // DIV is the upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    reload: Reload,
+}
+
+// TIMA reads 0 for a cycle after overflowing, then gets TMA and the interrupt
+// is requested. Writes behave differently during each of these steps
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reload {
+    None,
+    Overflowed(u8),
+    Reloading(u8),
+}
+
+impl Timer {
+    pub const DIV: u16 = 0xFF04;
+    pub const TIMA: u16 = 0xFF05;
+    pub const TMA: u16 = 0xFF06;
+    pub const TAC: u16 = 0xFF07;
+
+    const TAC_ENABLE: u8 = 0b100;
+    const TAC_UNUSED: u8 = 0b11111000;
+    const M_CYCLE: u8 = 4;
+    const HALF_M_CYCLE: u8 = 2;
+
+    pub fn new(divider: u16) -> Self {
+        Timer {
+            divider,
+            tima: 0,
+            tma: 0,
+            tac: 0,
+            reload: Reload::None,
+        }
+    }
+
+    // Bit of the divider watched by TIMA for each TAC clock select
+    fn divider_bit(tac: u8) -> u16 {
+        match tac & 0b11 {
+            0b00 => 1 << 9, // 4096 Hz
+            0b01 => 1 << 3, // 262144 Hz
+            0b10 => 1 << 5, // 65536 Hz
+            _ => 1 << 7,    // 16384 Hz
+        }
+    }
+
+    fn signal(divider: u16, tac: u8) -> bool {
+        tac & Timer::TAC_ENABLE != 0 && divider & Timer::divider_bit(tac) != 0
+    }
+
+    // TIMA is reloaded a M-cycle after overflowing. CPU writes land halfway
+    // through a M-cycle, so does the reload when they make TIMA overflow
+    fn increment_tima(&mut self, reload_delay: u8) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        if overflow {
+            self.reload = Reload::Overflowed(reload_delay);
+        }
+    }
+
+    // Advance by one clock cycle, returns true when the timer interrupt is requested
+    pub fn tick(&mut self) -> bool {
+        let mut interrupt = false;
+        self.reload = match self.reload {
+            Reload::Overflowed(1) => {
+                self.tima = self.tma;
+                interrupt = true;
+                Reload::Reloading(Timer::M_CYCLE)
+            }
+            Reload::Overflowed(cycles) => Reload::Overflowed(cycles - 1),
+            Reload::Reloading(1) => Reload::None,
+            Reload::Reloading(cycles) => Reload::Reloading(cycles - 1),
+            Reload::None => Reload::None,
+        };
+
+        let before = Timer::signal(self.divider, self.tac);
+        self.divider = self.divider.wrapping_add(1);
+        if before && !Timer::signal(self.divider, self.tac) {
+            self.increment_tima(Timer::M_CYCLE);
+        }
+
+        interrupt
+    }
+
+    pub fn read(&self, address: u16) -> u8 {
+        match address {
+            Timer::DIV => (self.divider >> 8) as u8,
+            Timer::TIMA => self.tima,
+            Timer::TMA => self.tma,
+            Timer::TAC => self.tac | Timer::TAC_UNUSED,
+            _ => panic!("Address {:#06x} is not a timer register", address),
+        }
+    }
+
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            Timer::DIV => {
+                // resetting the divider can produce a falling edge
+                if Timer::signal(self.divider, self.tac) {
+                    self.increment_tima(Timer::HALF_M_CYCLE);
+                }
+                self.divider = 0;
+            }
+            Timer::TIMA => match self.reload {
+                // writing during the overflow cycle cancels the reload
+                Reload::Overflowed(_) => {
+                    self.tima = data;
+                    self.reload = Reload::None;
+                }
+                // TMA is being copied, the write is lost
+                Reload::Reloading(_) => {}
+                Reload::None => self.tima = data,
+            },
+            Timer::TMA => {
+                self.tma = data;
+                if let Reload::Reloading(_) = self.reload {
+                    self.tima = data;
+                }
+            }
+            Timer::TAC => {
+                // the signal is the enable bit AND the selected divider bit, it falls
+                // when the timer is disabled or the new divider bit is cleared
+                let before = Timer::signal(self.divider, self.tac);
+                self.tac = data & !Timer::TAC_UNUSED;
+                if before && !Timer::signal(self.divider, self.tac) {
+                    self.increment_tima(Timer::HALF_M_CYCLE);
+                }
+            }
+            _ => panic!("Address {:#06x} is not a timer register", address),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // TIMA clocked by bit 3 of the divider
+    const FAST_TAC: u8 = Timer::TAC_ENABLE | 0b01;
+
+    // a timer whose next tick makes TIMA overflow
+    fn overflowing_timer() -> Timer {
+        let mut timer = Timer::new(0x000F);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::TMA, 0x42);
+        timer.write(Timer::TIMA, 0xFF);
+        timer
+    }
+
+    #[test]
+    fn increments_tima_when_div_is_written() {
+        let mut timer = Timer::new(0x0008);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::DIV, 0);
+        assert_eq!(timer.read(Timer::TIMA), 1);
+        assert_eq!(timer.read(Timer::DIV), 0);
+
+        // the watched bit is already cleared, no falling edge
+        let mut timer = Timer::new(0x0100);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::DIV, 0);
+        assert_eq!(timer.read(Timer::TIMA), 0);
+    }
+
+    #[test]
+    fn increments_tima_when_tac_is_written() {
+        // disabling the timer while the watched bit is set
+        let mut timer = Timer::new(0x0008);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::TAC, 0b01);
+        assert_eq!(timer.read(Timer::TIMA), 1);
+
+        // selecting a cleared bit while the old one is set
+        let mut timer = Timer::new(0x0008);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::TAC, Timer::TAC_ENABLE | 0b10);
+        assert_eq!(timer.read(Timer::TIMA), 1);
+
+        // selecting another set bit keeps the signal high
+        let mut timer = Timer::new(0x0028);
+        timer.write(Timer::TAC, FAST_TAC);
+        timer.write(Timer::TAC, Timer::TAC_ENABLE | 0b10);
+        assert_eq!(timer.read(Timer::TIMA), 0);
+    }
+
+    #[test]
+    fn requests_interrupt_a_m_cycle_after_overflow() {
+        let mut timer = overflowing_timer();
+        assert!(!timer.tick());
+        for _ in 1..Timer::M_CYCLE {
+            assert_eq!(timer.read(Timer::TIMA), 0);
+            assert!(!timer.tick());
+        }
+        assert_eq!(timer.read(Timer::TIMA), 0);
+        assert!(timer.tick());
+        assert_eq!(timer.read(Timer::TIMA), 0x42);
+    }
+
+    #[test]
+    fn writing_tima_in_the_overflow_cycle_cancels_the_reload() {
+        let mut timer = overflowing_timer();
+        timer.tick();
+        timer.write(Timer::TIMA, 0x10);
+        assert!((0..Timer::M_CYCLE).all(|_| !timer.tick()));
+        assert_eq!(timer.read(Timer::TIMA), 0x10);
+    }
+
+    #[test]
+    fn ignores_tima_writes_in_the_reload_cycle() {
+        let mut timer = overflowing_timer();
+        while !timer.tick() {}
+        timer.write(Timer::TIMA, 0x10);
+        assert_eq!(timer.read(Timer::TIMA), 0x42);
+
+        // TMA writes go through to TIMA instead
+        timer.write(Timer::TMA, 0x24);
+        assert_eq!(timer.read(Timer::TIMA), 0x24);
+
+        // the next M-cycle accepts writes again
+        for _ in 0..Timer::M_CYCLE {
+            timer.tick();
+        }
+        timer.write(Timer::TIMA, 0x10);
+        assert_eq!(timer.read(Timer::TIMA), 0x10);
+    }
+}
@@ -1,100 +1,156 @@
+use crate::bindings::{Button, Input, KeyBindings};
 use crate::bus::Bus;
 
-use minifb::{Key, Window};
-
-struct SelectedRows {
-    pub first: bool,
-    pub second: bool,
-}
-
-impl SelectedRows {
-    pub fn fetch(bus: &Bus) -> SelectedRows {
-        let row = (bus.fetch_byte(Buttons::BUTTON_REGISTER_ADDRESS) & 0b110000) >> 4;
-        SelectedRows {
-            first: row & 1 == 0,
-            second: row & 0b10 == 0,
-        }
-    }
-}
+use minifb::Window;
 
 pub struct Buttons {
-    row_1: u8,
-    row_2: u8,
+    row_1: u8, // directions
+    row_2: u8, // A, B, Select and Start
+    bindings: KeyBindings,
+    turbo_frames: [Option<u32>; 2], // frames since each turbo key went down
 }
 
 impl Buttons {
-    const BUTTON_REGISTER_ADDRESS: u16 = 0xFF00;
-
-    pub fn new() -> Buttons {
+    pub fn new(bindings: KeyBindings) -> Buttons {
         Buttons {
             row_1: 0xF,
             row_2: 0xF,
+            bindings,
+            turbo_frames: [None; 2],
+        }
+    }
+
+    fn row(&mut self, button: Button) -> &mut u8 {
+        if button.is_direction() {
+            &mut self.row_1
+        } else {
+            &mut self.row_2
         }
     }
 
-    fn key_down(&mut self, key: Key) {
-        match key {
-            Key::Down => self.row_1 &= 0b11110111,
-            Key::Up => self.row_1 &= 0b11111011,
-            Key::Left => self.row_1 &= 0b11111101,
-            Key::Right => self.row_1 &= 0b11111110,
-            Key::Enter => self.row_2 &= 0b11110111, // Start Button
-            Key::Space => self.row_2 &= 0b11111011, // Select Button
-            Key::B => self.row_2 &= 0b11111101,
-            Key::A => self.row_2 &= 0b11111110,
-            _ => (),
-        };
+    fn key_down(&mut self, button: Button) {
+        *self.row(button) &= !button.mask();
     }
 
-    fn key_up(&mut self, key: Key) {
-        match key {
-            Key::Down => self.row_1 |= 0b1000,
-            Key::Up => self.row_1 |= 0b100,
-            Key::Left => self.row_1 |= 0b10,
-            Key::Right => self.row_1 |= 0b1,
-            Key::Enter => self.row_2 |= 0b1000, // Start Button
-            Key::Space => self.row_2 |= 0b100,  // Select Button
-            Key::B => self.row_2 |= 0b10,
-            Key::A => self.row_2 |= 0b1,
-            _ => (),
-        };
+    fn key_up(&mut self, button: Button) {
+        *self.row(button) |= button.mask();
     }
 
+    fn is_bound_key_down(&self, window: &Window, input: Input) -> bool {
+        self.bindings
+            .keys(input)
+            .iter()
+            .any(|key| window.is_key_down(*key))
+    }
+
+    // Called once per emulated frame: turbo buttons are held for `turbo_rate`
+    // frames then released for as long, counting from when their key went down
     pub fn update_keys(&mut self, window: &Window) {
-        let keys = vec![
-            Key::Down,
-            Key::Up,
-            Key::Left,
-            Key::Right,
-            Key::Enter,
-            Key::Space,
-            Key::B,
-            Key::A,
-        ];
-
-        for key in keys {
-            if window.is_key_down(key) {
-                self.key_down(key);
+        let held: Vec<Input> = Input::TURBO
+            .iter()
+            .copied()
+            .chain(Button::ALL.iter().map(|button| Input::Button(*button)))
+            .filter(|input| self.is_bound_key_down(window, *input))
+            .collect();
+        self.update_inputs(&held);
+    }
+
+    fn update_inputs(&mut self, held: &[Input]) {
+        let mut turbo_pressed = Vec::new();
+        for (index, input) in Input::TURBO.iter().copied().enumerate() {
+            if !held.contains(&input) {
+                self.turbo_frames[index] = None;
+                continue;
+            }
+            let frames = self.turbo_frames[index].map_or(0, |frames| frames + 1);
+            self.turbo_frames[index] = Some(frames);
+            if (frames / self.bindings.turbo_rate()).is_multiple_of(2) {
+                turbo_pressed.push(Input::Button(input.button()));
+            }
+        }
+
+        for button in Button::ALL {
+            let input = Input::Button(button);
+            if held.contains(&input) || turbo_pressed.contains(&input) {
+                self.key_down(button);
             } else {
-                self.key_up(key);
+                self.key_up(button);
             }
         }
     }
 
+    // Both rows packed in a byte, buttons in the upper nibble, 0 meaning pressed
+    pub fn state(&self) -> u8 {
+        (self.row_2 << 4) | self.row_1
+    }
+
+    // Replace the keys state, when it comes from a movie instead of the window
+    pub fn set_state(&mut self, state: u8) {
+        self.row_1 = state & 0xF;
+        self.row_2 = state >> 4;
+    }
+
+    // Hand the keys state over to the joypad, which raises its interrupt if needed
     pub fn update_register(&self, bus: &mut Bus) {
-        let rows = SelectedRows::fetch(bus);
-        if rows.first {
-            // 4 upper bits are set to 1 to keep from reading more values
-            bus.set_byte(
-                Buttons::BUTTON_REGISTER_ADDRESS,
-                (self.row_1 & 0xF) | 0b11110000,
-            );
-        // update register with direction keys values
-        } else if rows.second {
-            bus.set_byte(
-                Buttons::BUTTON_REGISTER_ADDRESS,
-                (self.row_2 & 0xF) | 0b11110000,
-            );
-        }
+        bus.set_joypad_keys(self.row_1, self.row_2);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const A_PRESSED: u8 = 0b11101111;
+    const RELEASED: u8 = 0xFF;
+
+    fn buttons(turbo_rate: u32) -> Buttons {
+        let content = format!("turbo_rate = {}", turbo_rate);
+        Buttons::new(KeyBindings::parse(&content, "keys.cfg").unwrap())
+    }
+
+    // state of each frame with the given inputs held
+    fn states(buttons: &mut Buttons, held: &[Input], frames: usize) -> Vec<u8> {
+        (0..frames)
+            .map(|_| {
+                buttons.update_inputs(held);
+                buttons.state()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn toggles_turbo_every_turbo_rate_frames() {
+        let turbo_a = [Input::Turbo(Button::A)];
+        let mut keys = buttons(1);
+        assert_eq!(
+            states(&mut keys, &turbo_a, 4),
+            [A_PRESSED, RELEASED, A_PRESSED, RELEASED]
+        );
+        let mut keys = buttons(3);
+        assert_eq!(
+            states(&mut keys, &turbo_a, 7),
+            [A_PRESSED, A_PRESSED, A_PRESSED, RELEASED, RELEASED, RELEASED, A_PRESSED]
+        );
+    }
+
+    #[test]
+    fn restarts_turbo_when_key_goes_down_again() {
+        let turbo_a = [Input::Turbo(Button::A)];
+        let mut keys = buttons(2);
+        assert_eq!(states(&mut keys, &turbo_a, 3)[2], RELEASED);
+        assert_eq!(states(&mut keys, &[], 1), [RELEASED]);
+        assert_eq!(states(&mut keys, &turbo_a, 1), [A_PRESSED]);
+    }
+
+    #[test]
+    fn holds_button_pressed_with_turbo() {
+        let held = [Input::Turbo(Button::A), Input::Button(Button::A)];
+        let mut keys = buttons(1);
+        assert_eq!(states(&mut keys, &held, 3), [A_PRESSED; 3]);
+
+        // turbo B doesn't affect A
+        let held = [Input::Turbo(Button::B), Input::Button(Button::Right)];
+        let mut keys = buttons(1);
+        assert_eq!(states(&mut keys, &held, 2), [0b11011110, 0b11111110]);
     }
 }
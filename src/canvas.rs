@@ -2,8 +2,6 @@ use std::ops::Deref;
 
 use crate::color::Color;
 
-use minifb::Window;
-
 pub struct Canvas {
     buffer: Vec<u32>,
     x_size: usize,
@@ -14,7 +12,6 @@ pub struct Canvas {
 #[derive(Debug)]
 pub enum CanvasFail {
     IndexOutOfBounds,
-    WindowUpdate,
 }
 
 impl Canvas {
@@ -32,21 +29,21 @@ impl Canvas {
     }
 
     pub fn set_pixel(&mut self, x: usize, y: usize) -> Result<(), CanvasFail> {
-        if x > self.x_size || y > self.y_size {
+        if x >= self.x_size || y >= self.y_size {
             return Err(CanvasFail::IndexOutOfBounds);
         }
 
-        self.buffer[y * self.y_size + x] = self.current_draw_color.as_u32();
+        self.buffer[y * self.x_size + x] = self.current_draw_color.as_u32();
         Ok(())
     }
 
     #[allow(dead_code)]
     pub fn get_pixel(&self, x: usize, y: usize) -> Result<u32, CanvasFail> {
-        if x > self.x_size || y > self.y_size {
+        if x >= self.x_size || y >= self.y_size {
             return Err(CanvasFail::IndexOutOfBounds);
         }
 
-        Ok(self.buffer[y * self.y_size + x])
+        Ok(self.buffer[y * self.x_size + x])
     }
 
     pub fn fill_with_color(&mut self) {
@@ -57,17 +54,9 @@ impl Canvas {
             }
         }
     }
-
-    // TODO: remove from there
-    pub fn update_window(&self, window: &mut Window) -> Result<(), CanvasFail> {
-        match window.update_with_buffer(&self.buffer[..], self.x_size, self.y_size) {
-            Ok(_) => Ok(()),
-            Err(_) => Err(CanvasFail::WindowUpdate),
-        }
-    }
 }
 
-impl<'a> Deref for Canvas {
+impl Deref for Canvas {
     type Target = Vec<u32>;
 
     fn deref(&self) -> &Self::Target {
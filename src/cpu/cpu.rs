@@ -1,9 +1,8 @@
-use derive_more::Display;
-
 use super::instructions::*;
 use super::registers::*;
 
-use crate::bus::Bus;
+use crate::bus::{Bus, CgbSupport};
+use crate::model::Model;
 
 struct InterruptFlags {
     vblank: bool,
@@ -47,41 +46,7 @@ impl InterruptFlags {
     }
 }
 
-#[derive(Debug, Display, Clone, Copy)]
-pub enum Registers {
-    AF,
-    A,
-    BC,
-    B,
-    C,
-    DE,
-    D,
-    E,
-    HL,
-    H,
-    L,
-    SP,
-}
-
-impl Registers {
-    pub fn as_str(&self) -> &str {
-        match self {
-            Self::AF => "AF",
-            Self::A => "A",
-            Self::BC => "BC",
-            Self::B => "B",
-            Self::C => "C",
-            Self::DE => "DE",
-            Self::D => "D",
-            Self::E => "E",
-            Self::HL => "HL",
-            Self::H => "H",
-            Self::L => "L",
-            Self::SP => "SP",
-        }
-    }
-}
-
+#[allow(clippy::upper_case_acronyms)]
 pub struct CPU {
     pub af: AFRegister,
     pub bc: Register,
@@ -89,26 +54,67 @@ pub struct CPU {
     pub hl: Register,
     pub sp: u16,
     pub pc: u16,
-    clock_cycles_to_go: u8,
     pub stopped: bool,
     pub halted: bool,
+    pub halt_bug: bool,
+    pub locked: bool, // an illegal opcode hangs the CPU until power off
     pub ime: bool,
+    pub ime_scheduled: bool, // EI takes effect after the next instruction
 }
 
 impl CPU {
-    pub fn new_cpu() -> CPU {
-        CPU {
+    const INTERRUPT_ENABLE: u16 = 0xFFFF;
+    const INTERRUPT_FLAG: u16 = 0xFF0F;
+    const M_CYCLE: u8 = 4;
+
+    // Create a CPU in the state the boot ROM of the given model leaves it in,
+    // or in power on state if the bus has a boot ROM to run
+    pub fn new_cpu(model: Model, bus: &Bus) -> CPU {
+        let mut cpu = CPU {
             af: AFRegister::new(),
             bc: Register::new(),
             de: Register::new(),
             hl: Register::new(),
-            sp: 0,
+            sp: 0xFFFE,
             pc: 0x100,
-            clock_cycles_to_go: 0,
             stopped: false,
             halted: false,
+            halt_bug: false,
+            locked: false,
             ime: false,
+            ime_scheduled: false,
+        };
+
+        if bus.is_boot_rom_mapped() {
+            cpu.sp = 0;
+            cpu.pc = 0;
+            return cpu;
         }
+
+        // half carry and carry are only set if the header checksum is not zero
+        let header = bus.cartridge_header();
+        let checksum_flags = if header.header_checksum == 0 {
+            0x80
+        } else {
+            0xB0
+        };
+        let cgb_cartridge = header.cgb_support != CgbSupport::None;
+        let (af, bc, de, hl) = match model {
+            Model::DMG0 => (0x0100, 0xFF13, 0x00C1, 0x8403),
+            Model::DMG => (0x0100 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::MGB => (0xFF00 | checksum_flags, 0x0013, 0x00D8, 0x014D),
+            Model::SGB => (0x0100, 0x0014, 0x0000, 0xC060),
+            Model::SGB2 => (0xFF00, 0x0014, 0x0000, 0xC060),
+            Model::CGB if cgb_cartridge => (0x1180, 0x0000, 0xFF56, 0x000D),
+            // DMG compatibility mode
+            Model::CGB => (0x1180, 0x0000, 0x0008, 0x007C),
+        };
+        cpu.set_register(Registers::AF, af);
+        cpu.set_register(Registers::BC, bc);
+        cpu.set_register(Registers::DE, de);
+        cpu.set_register(Registers::HL, hl);
+
+        cpu
     }
 
     pub fn get_register_word(&self, reg: Registers) -> u16 {
@@ -118,42 +124,30 @@ impl CPU {
             Registers::DE => self.de.get_combined(),
             Registers::HL => self.hl.get_combined(),
             Registers::SP => self.sp,
-            _ => panic!(
-                "Trying to access word sized data on byte sized register {}",
-                reg
-            ),
         }
     }
 
-    pub fn get_register_byte(&self, reg: Registers) -> u8 {
+    pub fn get_register_byte(&self, reg: HalfRegisters) -> u8 {
         match reg {
-            Registers::A => self.af.a,
-            Registers::B => self.bc.low,
-            Registers::C => self.bc.high,
-            Registers::D => self.de.low,
-            Registers::E => self.de.high,
-            Registers::H => self.hl.low,
-            Registers::L => self.hl.high,
-            _ => panic!(
-                "Trying to access byte sized data on word sized register {}",
-                reg
-            ),
+            HalfRegisters::A => self.af.a,
+            HalfRegisters::B => self.bc.high,
+            HalfRegisters::C => self.bc.low,
+            HalfRegisters::D => self.de.high,
+            HalfRegisters::E => self.de.low,
+            HalfRegisters::H => self.hl.high,
+            HalfRegisters::L => self.hl.low,
         }
     }
 
-    pub fn set_half_register(&mut self, reg: Registers, data: u8) {
+    pub fn set_half_register(&mut self, reg: HalfRegisters, data: u8) {
         match reg {
-            Registers::A => self.af.a = data,
-            Registers::B => self.bc.low = data,
-            Registers::C => self.bc.high = data,
-            Registers::D => self.de.low = data,
-            Registers::E => self.de.high = data,
-            Registers::H => self.hl.low = data,
-            Registers::L => self.hl.high = data,
-            _ => panic!(
-                "Trying to set byte sized data in word sized register {}",
-                reg
-            ),
+            HalfRegisters::A => self.af.a = data,
+            HalfRegisters::B => self.bc.high = data,
+            HalfRegisters::C => self.bc.low = data,
+            HalfRegisters::D => self.de.high = data,
+            HalfRegisters::E => self.de.low = data,
+            HalfRegisters::H => self.hl.high = data,
+            HalfRegisters::L => self.hl.low = data,
         }
     }
 
@@ -164,101 +158,411 @@ impl CPU {
             Registers::DE => self.de.set_word(data),
             Registers::HL => self.hl.set_word(data),
             Registers::SP => self.sp = data,
-            _ => panic!(
-                "Trying to set word sized data in byte sized register {}",
-                reg
-            ),
         }
     }
 
-    pub fn get_flag(&self, flag: char) -> bool {
+    pub fn get_flag(&self, flag: Flag) -> bool {
         self.af.flags.get(flag)
     }
 
-    pub fn set_flag(&mut self, flag: char) {
+    pub fn set_flag(&mut self, flag: Flag) {
         self.af.flags.set(flag)
     }
 
-    pub fn clear_flag(&mut self, flag: char) {
+    pub fn clear_flag(&mut self, flag: Flag) {
         self.af.flags.clear(flag)
     }
 
-    pub fn update_flag(&mut self, flag: char, value: bool) {
+    pub fn update_flag(&mut self, flag: Flag, value: bool) {
         self.af.flags.update(flag, value)
     }
 
-    pub fn tick(&mut self, bus: &mut Bus) {
-        // execute a tick of the CPU
-        /*let timer = bus.fetch_byte(0xFF04); // timer register to be incremented
-        bus.set_byte(0xFF04, timer.wrapping_add(1));*/
+    // Execute the next instruction or service an interrupt. The bus moves
+    // forward a M-cycle on every memory access, the cycles left without one
+    // are spent once the instruction is done
+    pub fn step(&mut self, bus: &mut Bus) {
+        if self.locked {
+            // nothing wakes it up, not even interrupts, the rest goes on
+            bus.tick_m_cycle();
+            return;
+        }
+        if self.stopped {
+            // only the joypad wakes the CPU up from STOP
+            if !bus.is_joypad_pressed() {
+                bus.tick_stopped_m_cycle();
+                return;
+            }
+            self.stopped = false;
+        }
+        if self.halted {
+            // stay idle until an enabled interrupt is requested, even if IME is cleared
+            if !self.has_pending_interrupt(bus) {
+                bus.tick_m_cycle();
+                return;
+            }
+            self.halted = false;
+            // waking up takes an extra cycle unless the interrupt was requested
+            // in time to be dispatched, whether or not it is serviced
+            if bus.dispatchable_interrupts() == 0 {
+                bus.tick_m_cycle();
+            }
+        }
 
-        if self.clock_cycles_to_go > 0 {
-            self.clock_cycles_to_go -= 1;
+        let start = bus.cycles();
+        let cycles = if self.ime && bus.dispatchable_interrupts() != 0 {
+            self.dispatch_interrupt(bus, false)
         } else {
-            self.execute_instruction(bus);
+            self.execute_instruction(bus)
+        };
+        while bus.cycles() - start < cycles as u64 {
+            bus.tick_m_cycle();
         }
     }
 
-    fn execute_instruction(&mut self, bus: &mut Bus) {
+    pub fn has_pending_interrupt(&self, bus: &Bus) -> bool {
+        bus.fetch_byte(CPU::INTERRUPT_ENABLE) & bus.fetch_byte(CPU::INTERRUPT_FLAG) & 0b11111 != 0
+    }
+
+    fn execute_instruction(&mut self, bus: &mut Bus) -> u8 {
+        let ime = self.ime;
+        // an EI right before is only effective once this instruction is done
+        let ime_scheduled = self.ime_scheduled;
+
         // fetch and execute instruction at program counter
         let instruction = Instruction::fetch_new(bus, self);
-        println!("{:#06x}:  {}", self.pc, instruction);
-        self.clock_cycles_to_go += instruction.execute(bus, self);
-
-        // interrupts
-        if self.ime == true {
-            self.check_for_interrupts(bus);
+        // an interrupt requested early enough is serviced instead of the opcode
+        if ime && bus.early_interrupts() != 0 {
+            return self.dispatch_interrupt(bus, true);
+        }
+        if self.halt_bug {
+            // PC fails to move past the opcode, so it is read twice
+            self.halt_bug = false;
+            self.pc = self.pc.wrapping_sub(1);
         }
+        let cycles = instruction.execute(bus, self);
+        // unless that instruction was a DI
+        if ime_scheduled && self.ime_scheduled {
+            self.ime_scheduled = false;
+            self.ime = true;
+        }
+        cycles
     }
 
-    fn check_for_interrupts(&mut self, bus: &mut Bus) {
-        let enabled = InterruptFlags::from_byte(bus.fetch_byte(0xFFFF));
-        let mut requested = InterruptFlags::from_byte(bus.fetch_byte(0xFF0F));
-        if enabled.vblank && requested.vblank {
-            self.ime = false;
+    // Push PC and jump to the vector of the highest priority interrupt, in 5 cycles.
+    // The first one fetches an opcode that is discarded, unless already done.
+    // The vector is chosen after the high byte of PC is pushed: if that push
+    // overwrote IE and nothing is left to service, PC ends up at 0x0000
+    fn dispatch_interrupt(&mut self, bus: &mut Bus, opcode_fetched: bool) -> u8 {
+        self.ime = false;
+        self.ime_scheduled = false;
+        if !opcode_fetched {
+            bus.tick_m_cycle();
+        }
+        bus.tick_m_cycle();
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write_cycle(self.sp, (self.pc >> 8) as u8);
+
+        let enabled = InterruptFlags::from_byte(bus.fetch_byte(CPU::INTERRUPT_ENABLE));
+        let flags = bus.fetch_byte(CPU::INTERRUPT_FLAG);
+        let mut requested = InterruptFlags::from_byte(flags);
+        let vector = if enabled.vblank && requested.vblank {
             requested.vblank = false;
-            self.push_word_to_stack(bus, self.pc);
-            self.pc = 0x40;
+            0x40
         } else if enabled.lcd_stat && requested.lcd_stat {
-            self.ime = false;
             requested.lcd_stat = false;
-            self.push_word_to_stack(bus, self.pc);
-            self.pc = 0x48;
+            0x48
         } else if enabled.timer && requested.timer {
-            self.ime = false;
             requested.timer = false;
-            self.push_word_to_stack(bus, self.pc);
-            self.pc = 0x50;
+            0x50
         } else if enabled.serial && requested.serial {
-            self.ime = false;
             requested.serial = false;
-            self.push_word_to_stack(bus, self.pc);
-            self.pc = 0x58;
+            0x58
         } else if enabled.joypad && requested.joypad {
-            self.ime = false;
             requested.joypad = false;
-            self.push_word_to_stack(bus, self.pc);
-            self.pc = 0x60;
-        }
-        bus.set_byte(0xFF0F, requested.to_byte());
+            0x60
+        } else {
+            0x0000
+        };
+        let serviced = flags & 0b11111 & !requested.to_byte();
+
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write_cycle(self.sp, self.pc as u8);
+        self.pc = vector;
+        // the request is cleared last, the same one repeated meanwhile is lost
+        bus.acknowledge_interrupt(serviced);
+
+        5 * CPU::M_CYCLE
     }
 
+    // High byte first, each byte takes a M-cycle
     pub fn push_word_to_stack(&mut self, bus: &mut Bus, data: u16) {
-        self.sp -= 2;
-        bus.set_word(self.sp, data);
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write_cycle(self.sp, (data >> 8) as u8);
+        self.sp = self.sp.wrapping_sub(1);
+        bus.write_cycle(self.sp, data as u8);
     }
 
-    pub fn pop_word_from_stack(&mut self, bus: &Bus) -> u16 {
-        let data = bus.fetch_word(self.sp);
-        self.sp += 2;
+    pub fn pop_word_from_stack(&mut self, bus: &mut Bus) -> u16 {
+        let data = bus.read_word_cycles(self.sp);
+        self.sp = self.sp.wrapping_add(2);
         data
     }
 
-    pub fn peek_bus_byte(&self, bus: &Bus) -> u8 {
-        bus.fetch_byte(self.pc)
+    pub fn peek_bus_byte(&self, bus: &mut Bus) -> u8 {
+        bus.read_cycle(self.pc)
+    }
+
+    pub fn peek_bus_word(&self, bus: &mut Bus) -> u16 {
+        bus.read_word_cycles(self.pc)
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMER: u8 = 0b100;
+    const EI: u8 = 0xFB;
+    const DI: u8 = 0xF3;
+    const HALT: u8 = 0x76;
+    const INC_A: u8 = 0x3C;
+    const NOP: u8 = 0x00;
 
-    pub fn peek_bus_word(&self, bus: &Bus) -> u16 {
-        bus.fetch_word(self.pc)
+    // program copied to WRAM, with the timer interrupt enabled and requested
+    fn run(program: &[u8], ime: bool) -> (CPU, Bus) {
+        let mut bus = Bus::from_cartridge(vec![0; 0x8000], Model::DMG).unwrap();
+        let mut cpu = CPU::new_cpu(Model::DMG, &bus);
+        for (offset, &opcode) in program.iter().enumerate() {
+            bus.set_byte(0xC000 + offset as u16, opcode);
+        }
+        bus.set_byte(CPU::INTERRUPT_ENABLE, TIMER);
+        bus.set_byte(CPU::INTERRUPT_FLAG, TIMER);
+        cpu.pc = 0xC000;
+        cpu.ime = ime;
+        (cpu, bus)
+    }
+
+    fn return_address(cpu: &CPU, bus: &Bus) -> u16 {
+        u16::from_le_bytes([bus.fetch_byte(cpu.sp), bus.fetch_byte(cpu.sp + 1)])
+    }
+
+    #[test]
+    fn dispatches_interrupt_an_instruction_after_ei() {
+        let (mut cpu, mut bus) = run(&[EI, NOP, NOP], false);
+        cpu.step(&mut bus);
+        assert!(!cpu.ime);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0xC002);
+        assert!(cpu.ime);
+
+        let start = bus.cycles();
+        cpu.step(&mut bus);
+        assert_eq!(bus.cycles() - start, 20);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(return_address(&cpu, &bus), 0xC002);
+        assert!(!cpu.ime);
+        assert_eq!(bus.fetch_byte(CPU::INTERRUPT_FLAG) & TIMER, 0);
+    }
+
+    #[test]
+    fn cancels_ei_with_di() {
+        let (mut cpu, mut bus) = run(&[EI, DI, NOP], false);
+        for _ in 0..3 {
+            cpu.step(&mut bus);
+        }
+        assert_eq!(cpu.pc, 0xC003);
+        assert!(!cpu.ime && !cpu.ime_scheduled);
+    }
+
+    #[test]
+    fn returns_to_halt_after_ei() {
+        let (mut cpu, mut bus) = run(&[EI, HALT, NOP], false);
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert!(!cpu.halted && !cpu.halt_bug);
+        assert!(cpu.ime);
+
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(return_address(&cpu, &bus), 0xC001);
+    }
+
+    #[test]
+    fn reads_opcode_after_halt_twice_with_ime_cleared() {
+        let (mut cpu, mut bus) = run(&[HALT, INC_A, NOP], false);
+        let a = cpu.af.a;
+        cpu.step(&mut bus);
+        assert!(cpu.halt_bug && !cpu.halted);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0xC001);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.pc, 0xC002);
+        assert_eq!(cpu.af.a, a.wrapping_add(2));
+    }
+
+    #[test]
+    fn wakes_up_from_halt_on_interrupt() {
+        let (mut cpu, mut bus) = run(&[HALT, NOP], true);
+        bus.set_byte(CPU::INTERRUPT_FLAG, 0);
+        cpu.step(&mut bus);
+        assert!(cpu.halted);
+        cpu.step(&mut bus);
+        assert!(cpu.halted);
+
+        bus.set_byte(CPU::INTERRUPT_FLAG, TIMER);
+        cpu.step(&mut bus);
+        assert!(!cpu.halted);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(return_address(&cpu, &bus), 0xC001);
+    }
+
+    #[test]
+    fn maps_the_first_register_of_a_pair_to_the_high_byte() {
+        let (mut cpu, _) = run(&[], false);
+        cpu.set_register(Registers::BC, 0x1234);
+        cpu.set_register(Registers::DE, 0x5678);
+        cpu.set_register(Registers::HL, 0x9ABC);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::B), 0x12);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::C), 0x34);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::D), 0x56);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::E), 0x78);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::H), 0x9A);
+        assert_eq!(cpu.get_register_byte(HalfRegisters::L), 0xBC);
+
+        cpu.set_half_register(HalfRegisters::B, 0xDE);
+        cpu.set_half_register(HalfRegisters::C, 0xAD);
+        cpu.set_half_register(HalfRegisters::D, 0xBE);
+        cpu.set_half_register(HalfRegisters::E, 0xEF);
+        cpu.set_half_register(HalfRegisters::H, 0xCA);
+        cpu.set_half_register(HalfRegisters::L, 0xFE);
+        assert_eq!(cpu.get_register_word(Registers::BC), 0xDEAD);
+        assert_eq!(cpu.get_register_word(Registers::DE), 0xBEEF);
+        assert_eq!(cpu.get_register_word(Registers::HL), 0xCAFE);
+    }
+
+    #[test]
+    fn locks_up_on_illegal_opcodes() {
+        let (mut cpu, mut bus) = run(&[0xD3, NOP], true);
+        bus.set_byte(CPU::INTERRUPT_FLAG, 0);
+        cpu.step(&mut bus);
+        assert!(cpu.locked);
+        let pc = cpu.pc;
+
+        bus.set_byte(CPU::INTERRUPT_FLAG, TIMER);
+        let start = bus.cycles();
+        for _ in 0..10 {
+            cpu.step(&mut bus);
+        }
+        // neither the next opcode nor the interrupt is reached
+        assert_eq!(cpu.pc, pc);
+        assert!(cpu.ime);
+        assert_eq!(bus.cycles() - start, 40);
+    }
+
+    #[test]
+    fn starts_in_the_state_left_by_each_boot_rom() {
+        // AF, BC, DE, HL, then LCDC, STAT, DIV, NR52 and P1
+        let cases = [
+            (
+                Model::DMG0,
+                [0x0100, 0xFF13, 0x00C1, 0x8403],
+                [0x91, 0x81, 0x18, 0xF1, 0xCF],
+            ),
+            (
+                Model::DMG,
+                [0x0180, 0x0013, 0x00D8, 0x014D],
+                [0x91, 0x84, 0xAB, 0xF1, 0xCF],
+            ),
+            (
+                Model::MGB,
+                [0xFF80, 0x0013, 0x00D8, 0x014D],
+                [0x91, 0x84, 0xAB, 0xF1, 0xCF],
+            ),
+            (
+                Model::SGB,
+                [0x0100, 0x0014, 0x0000, 0xC060],
+                [0x91, 0x84, 0xD8, 0xF0, 0xFF],
+            ),
+            (
+                Model::SGB2,
+                [0xFF00, 0x0014, 0x0000, 0xC060],
+                [0x91, 0x84, 0xD8, 0xF0, 0xFF],
+            ),
+            (
+                Model::CGB,
+                [0x1180, 0x0000, 0x0008, 0x007C],
+                [0x91, 0x84, 0x1E, 0xF1, 0xCF],
+            ),
+        ];
+        for (model, registers, io) in &cases {
+            let bus = Bus::from_cartridge(vec![0; 0x8000], *model).unwrap();
+            let cpu = CPU::new_cpu(*model, &bus);
+            let words = [Registers::AF, Registers::BC, Registers::DE, Registers::HL]
+                .map(|reg| cpu.get_register_word(reg));
+            assert_eq!(&words, registers, "{}", model);
+            assert_eq!((cpu.sp, cpu.pc), (0xFFFE, 0x0100), "{}", model);
+            let io_read =
+                [0xFF40, 0xFF41, 0xFF04, 0xFF26, 0xFF00].map(|address| bus.fetch_byte(address));
+            assert_eq!(&io_read, io, "{}", model);
+        }
+    }
+
+    #[test]
+    fn freezes_the_clock_until_a_key_is_pressed() {
+        const STOP: u8 = 0x10;
+        const P1: u16 = 0xFF00;
+        const DIV: u16 = 0xFF04;
+        const LY: u16 = 0xFF44;
+        let (mut cpu, mut bus) = run(&[STOP, 0x00, INC_A], false);
+        // directions selected
+        bus.set_byte(P1, 0x20);
+        let ly = bus.fetch_byte(LY);
+        cpu.step(&mut bus);
+        assert!(cpu.stopped);
+        assert_eq!(bus.fetch_byte(DIV), 0);
+
+        for _ in 0..1000 {
+            cpu.step(&mut bus);
+        }
+        assert!(cpu.stopped);
+        assert_eq!(cpu.pc, 0xC002);
+        assert_eq!(bus.fetch_byte(DIV), 0);
+        assert_eq!(bus.fetch_byte(LY), ly);
+
+        // right pressed
+        bus.set_joypad_keys(0b1110, 0b1111);
+        cpu.step(&mut bus);
+        assert!(!cpu.stopped);
+        assert_eq!(cpu.pc, 0xC003);
+        for _ in 0..100 {
+            bus.tick_m_cycle();
+        }
+        assert_ne!(bus.fetch_byte(DIV), 0);
+    }
+
+    #[test]
+    fn reads_memory_on_the_m_cycle_of_the_access() {
+        const INC_HL_POINTER: u8 = 0x34;
+        const LY: u16 = 0xFF44;
+        // M-cycles until LY goes from 15 to 16
+        let (_, mut bus) = run(&[], false);
+        let mut m_cycles = 0;
+        while bus.fetch_byte(LY) != 16 {
+            bus.tick_m_cycle();
+            m_cycles += 1;
+        }
+
+        // INC (HL) reads on its 2nd M-cycle out of 3, the half carry shows
+        // whether it saw line 15 or 16
+        for &(early, half_carry) in &[(3, true), (2, false)] {
+            let (mut cpu, mut bus) = run(&[INC_HL_POINTER], false);
+            cpu.set_register(Registers::HL, LY);
+            for _ in 0..m_cycles - early {
+                bus.tick_m_cycle();
+            }
+            cpu.step(&mut bus);
+            assert_eq!(bus.cycles(), 4 * (m_cycles - early + 3));
+            assert_eq!(cpu.get_flag(Flag::HalfCarry), half_carry, "{} early", early);
+        }
     }
 }
@@ -2,6 +2,7 @@ use std::fmt;
 use std::fmt::{Display, Formatter};
 
 use super::cpu::*;
+use super::registers::*;
 use super::sized::*;
 use super::target::*;
 
@@ -12,60 +13,187 @@ pub enum Operation {
     Nop,
     LD,
     Jmp(Condition),
+    JmpRelative(Condition),
+    Call(Condition),
+    Ret(Condition),
+    Reti,
+    Rst(u16),
+    Push,
     Pop,
 
+    Add,
+    Adc,
+    Sub,
+    Sbc,
+    And,
+    Or,
+    Xor,
+    Cp,
+    AddWord,
+    AddSignedToSP, // ADD SP,e8 and LD HL,SP+e8
+
     IncByte,
     IncWord,
+    DecByte,
+    DecWord,
+
+    Rlca,
+    Rrca,
+    Rla,
+    Rra,
+
+    Daa,
+    Cpl,
+    Scf,
+    Ccf,
 
-    CPL,
+    Di,
+    Ei,
+    Halt,
+    Stop,
+
+    // CB prefixed
+    Rlc,
+    Rrc,
+    Rl,
+    Rr,
+    Sla,
+    Sra,
+    Srl,
+    Swap,
+    Bit(u8),
+    Res(u8),
+    Set(u8),
 
     Illegal,
 }
 
 impl Operation {
-    pub fn should_advance_pc(&self) -> bool {
+    // Condition deciding whether a branch is taken, always met for other operations
+    pub fn condition(&self) -> Condition {
         match self {
-            Operation::Jmp(_) => false,
-            _ => true,
+            Self::Jmp(cond) | Self::JmpRelative(cond) | Self::Call(cond) | Self::Ret(cond) => *cond,
+            _ => Condition::None,
         }
     }
 
-    pub fn execute(&self, bus: &Bus, cpu: &mut CPU, source: Sized) -> Sized {
+    pub fn execute(&self, bus: &mut Bus, cpu: &mut CPU, source: Sized) -> Sized {
         match self {
             Self::Nop => Sized::Zero,
             Self::LD => source,
             Self::Jmp(cond) => jmp(cpu, source, cond),
+            Self::JmpRelative(cond) => jmp_relative(cpu, source, cond),
+            Self::Call(cond) => call(bus, cpu, source, cond),
+            Self::Ret(cond) => ret(bus, cpu, cond),
+            Self::Reti => reti(bus, cpu),
+            Self::Rst(vector) => rst(bus, cpu, *vector),
+            Self::Push => push(bus, cpu, source),
             Self::Pop => pop(bus, cpu),
-            Self::CPL => cpl(cpu),
+
+            Self::Add => add(cpu, source, false),
+            Self::Adc => add(cpu, source, true),
+            Self::Sub => sub(cpu, source, false),
+            Self::Sbc => sub(cpu, source, true),
+            Self::And => and(cpu, source),
+            Self::Or => or(cpu, source),
+            Self::Xor => xor(cpu, source),
+            Self::Cp => cp(cpu, source),
+            Self::AddWord => add_word(cpu, source),
+            Self::AddSignedToSP => add_signed_to_sp(cpu, source),
+
             Self::IncByte => inc(cpu, source, true),
             Self::IncWord => inc(cpu, source, false),
+            Self::DecByte => dec(cpu, source, true),
+            Self::DecWord => dec(cpu, source, false),
 
-            Self::Illegal => panic!("Illegal instruction reached"),
-            _ => unimplemented!(),
+            Self::Rlca => rlca(cpu),
+            Self::Rrca => rrca(cpu),
+            Self::Rla => rla(cpu),
+            Self::Rra => rra(cpu),
+
+            Self::Daa => daa(cpu),
+            Self::Cpl => cpl(cpu),
+            Self::Scf => scf(cpu),
+            Self::Ccf => ccf(cpu),
+
+            Self::Di => di(cpu),
+            Self::Ei => ei(cpu),
+            Self::Halt => halt(bus, cpu),
+            Self::Stop => stop(bus, cpu),
+
+            Self::Rlc => rlc(cpu, source),
+            Self::Rrc => rrc(cpu, source),
+            Self::Rl => rl(cpu, source),
+            Self::Rr => rr(cpu, source),
+            Self::Sla => sla(cpu, source),
+            Self::Sra => sra(cpu, source),
+            Self::Srl => srl(cpu, source),
+            Self::Swap => swap(cpu, source),
+            Self::Bit(bit) => test_bit(cpu, source, *bit),
+            Self::Res(bit) => Sized::Byte(u8::from(source) & !(1 << bit)),
+            Self::Set(bit) => Sized::Byte(u8::from(source) | (1 << bit)),
+
+            Self::Illegal => lock(cpu),
         }
     }
 }
 
 impl Display for Operation {
     fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Self::Nop => "NOP",
-                Self::LD => "LD",
-                Self::Jmp(cond) => match cond {
-                    Condition::None => "JMP",
-                    Condition::NonZero => "JNZ",
-                    Condition::NoCarry => "JNC",
-                },
-                Self::Pop => "POP",
-                Self::CPL => "CPL",
-                Self::IncByte | Self::IncWord => "INC",
-
-                Self::Illegal => "Illegal",
-            }
-        )
+        match self {
+            Self::Jmp(cond) => write!(f, "JP{}", cond),
+            Self::JmpRelative(cond) => write!(f, "JR{}", cond),
+            Self::Call(cond) => write!(f, "CALL{}", cond),
+            Self::Ret(cond) => write!(f, "RET{}", cond),
+            Self::Rst(vector) => write!(f, "RST {:#04x}", vector),
+            Self::Bit(bit) => write!(f, "BIT {},", bit),
+            Self::Res(bit) => write!(f, "RES {},", bit),
+            Self::Set(bit) => write!(f, "SET {},", bit),
+            _ => write!(
+                f,
+                "{}",
+                match self {
+                    Self::Nop => "NOP",
+                    Self::LD => "LD",
+                    Self::Reti => "RETI",
+                    Self::Push => "PUSH",
+                    Self::Pop => "POP",
+                    Self::Add | Self::AddWord | Self::AddSignedToSP => "ADD",
+                    Self::Adc => "ADC",
+                    Self::Sub => "SUB",
+                    Self::Sbc => "SBC",
+                    Self::And => "AND",
+                    Self::Or => "OR",
+                    Self::Xor => "XOR",
+                    Self::Cp => "CP",
+                    Self::IncByte | Self::IncWord => "INC",
+                    Self::DecByte | Self::DecWord => "DEC",
+                    Self::Rlca => "RLCA",
+                    Self::Rrca => "RRCA",
+                    Self::Rla => "RLA",
+                    Self::Rra => "RRA",
+                    Self::Daa => "DAA",
+                    Self::Cpl => "CPL",
+                    Self::Scf => "SCF",
+                    Self::Ccf => "CCF",
+                    Self::Di => "DI",
+                    Self::Ei => "EI",
+                    Self::Halt => "HALT",
+                    Self::Stop => "STOP",
+                    Self::Rlc => "RLC",
+                    Self::Rrc => "RRC",
+                    Self::Rl => "RL",
+                    Self::Rr => "RR",
+                    Self::Sla => "SLA",
+                    Self::Sra => "SRA",
+                    Self::Srl => "SRL",
+                    Self::Swap => "SWAP",
+
+                    Self::Illegal => "Illegal",
+                    _ => unreachable!(),
+                }
+            ),
+        }
     }
 }
 
@@ -73,7 +201,37 @@ impl Display for Operation {
 pub enum Condition {
     None,
     NonZero,
+    Zero,
     NoCarry,
+    Carry,
+}
+
+impl Condition {
+    pub fn is_met(&self, cpu: &CPU) -> bool {
+        match self {
+            Condition::None => true,
+            Condition::NonZero => !cpu.get_flag(Flag::Zero),
+            Condition::Zero => cpu.get_flag(Flag::Zero),
+            Condition::NoCarry => !cpu.get_flag(Flag::Carry),
+            Condition::Carry => cpu.get_flag(Flag::Carry),
+        }
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Condition::None => "",
+                Condition::NonZero => " NZ",
+                Condition::Zero => " Z",
+                Condition::NoCarry => " NC",
+                Condition::Carry => " C",
+            }
+        )
+    }
 }
 
 pub struct Instruction {
@@ -82,33 +240,53 @@ pub struct Instruction {
     source: Target,
     dest: Target,
     clock_cycles: u8,
+    clock_cycles_not_taken: u8, // conditional branches are shorter when not taken
     op_byte_len: u16,
 }
 
 impl Instruction {
-    pub fn fetch_new(bus: &Bus, cpu: &CPU) -> Self {
-        Self::from_opcode(bus.fetch_byte(cpu.pc))
+    const PREFIX: u8 = 0xCB;
+
+    pub fn fetch_new(bus: &mut Bus, cpu: &CPU) -> Self {
+        match bus.read_cycle(cpu.pc) {
+            Self::PREFIX => Self::from_prefixed_opcode(bus.read_cycle(cpu.pc.wrapping_add(1))),
+            opcode => Self::from_opcode(opcode),
+        }
     }
 
     // Execute an instruction. Returns the number of clock cycles to wait
     pub fn execute(self, bus: &mut Bus, cpu: &mut CPU) -> u8 {
-        cpu.pc += self.op_byte_len;
-        let mut instruction_length = 0;
-        let source = self.source.fetch(bus, cpu);
-        instruction_length += source.number_of_bytes();
+        cpu.pc = cpu.pc.wrapping_add(self.op_byte_len);
+        let immediate = self.fetch_immediate(bus, cpu);
+        let taken = self.op.condition().is_met(cpu);
 
+        let source = self.source.fetch(bus, cpu, immediate);
         let result = self.op.execute(bus, cpu, source);
+        self.dest.write(bus, cpu, immediate, result);
 
-        self.dest.write(bus, cpu, result);
-
-        if self.op.should_advance_pc() {
-            cpu.pc += instruction_length;
+        if taken {
+            self.clock_cycles
+        } else {
+            self.clock_cycles_not_taken
         }
+    }
 
-        self.clock_cycles
+    // Read the bytes following the opcode and move PC past them, so that
+    // operations always see PC pointing to the next instruction
+    fn fetch_immediate(&self, bus: &mut Bus, cpu: &mut CPU) -> Sized {
+        let size = self.source.immediate_size().max(self.dest.immediate_size());
+        let immediate = match size {
+            1 => cpu.peek_bus_byte(bus).into(),
+            2 => cpu.peek_bus_word(bus).into(),
+            _ => Sized::Zero,
+        };
+        cpu.pc = cpu.pc.wrapping_add(size);
+
+        immediate
     }
 
     fn from_opcode(opcode: u8) -> Self {
+        use HalfRegisters::{A, C};
         use Registers::*;
         let mut result = match opcode {
             0x00 => Instruction {
@@ -117,6 +295,7 @@ impl Instruction {
                 source: Target::None,
                 dest: Target::None,
                 clock_cycles: 4,
+                clock_cycles_not_taken: 4,
                 op_byte_len: 1,
             },
 
@@ -125,172 +304,417 @@ impl Instruction {
             0x21 => Self::ld_d16(HL),
             0x31 => Self::ld_d16(SP),
 
-            0x06 => Self::ld_d8_imm(B),
-            0x0E => Self::ld_d8_imm(C),
-            0x16 => Self::ld_d8_imm(D),
-            0x1E => Self::ld_d8_imm(E),
-            0x26 => Self::ld_d8_imm(H),
-            0x2E => Self::ld_d8_imm(L),
+            0x02 => Self::ld_a_to_ptr(Target::IndirectRegister(BC)),
+            0x12 => Self::ld_a_to_ptr(Target::IndirectRegister(DE)),
+            0x22 => Self::ld_a_to_ptr(Target::IndirectRegisterIncrement(HL)),
+            0x32 => Self::ld_a_to_ptr(Target::IndirectRegisterDecrement(HL)),
+            0x0A => Self::ld_a_from_ptr(Target::IndirectRegister(BC)),
+            0x1A => Self::ld_a_from_ptr(Target::IndirectRegister(DE)),
+            0x2A => Self::ld_a_from_ptr(Target::IndirectRegisterIncrement(HL)),
+            0x3A => Self::ld_a_from_ptr(Target::IndirectRegisterDecrement(HL)),
+
+            0x06 | 0x0E | 0x16 | 0x1E | 0x26 | 0x2E | 0x36 | 0x3E => Self::ld_d8_imm(opcode),
 
-            0x40..=0x47 => Self::ld_d8_reg_to_reg_match(B, opcode),
-            0x48..=0x4F => Self::ld_d8_reg_to_reg_match(C, opcode),
-            0x50..=0x57 => Self::ld_d8_reg_to_reg_match(D, opcode),
-            0x58..=0x5F => Self::ld_d8_reg_to_reg_match(E, opcode),
-            0x60..=0x67 => Self::ld_d8_reg_to_reg_match(H, opcode),
-            0x68..=0x6F => Self::ld_d8_reg_to_reg_match(L, opcode),
+            0x40..=0x75 | 0x77..=0x7F => Self::ld_reg_to_reg(opcode),
+
+            0x08 => Self::with(
+                Operation::LD,
+                Target::Register(SP),
+                Target::IndirectImmediate,
+                20,
+            ),
+            0xE0 => Self::with(
+                Operation::LD,
+                Target::HalfRegister(A),
+                Target::IndirectIOPort,
+                12,
+            ),
+            0xF0 => Self::with(
+                Operation::LD,
+                Target::IndirectIOPort,
+                Target::HalfRegister(A),
+                12,
+            ),
+            0xE2 => Self::with(
+                Operation::LD,
+                Target::HalfRegister(A),
+                Target::IndirectIOPortRegister(C),
+                8,
+            ),
+            0xF2 => Self::with(
+                Operation::LD,
+                Target::IndirectIOPortRegister(C),
+                Target::HalfRegister(A),
+                8,
+            ),
+            0xEA => Self::with(
+                Operation::LD,
+                Target::HalfRegister(A),
+                Target::IndirectImmediate,
+                16,
+            ),
+            0xFA => Self::with(
+                Operation::LD,
+                Target::IndirectImmediate,
+                Target::HalfRegister(A),
+                16,
+            ),
+            0xF9 => Self::with(Operation::LD, Target::Register(HL), Target::Register(SP), 8),
 
             0x03 => Self::inc_reg16(BC),
             0x13 => Self::inc_reg16(DE),
             0x23 => Self::inc_reg16(HL),
             0x33 => Self::inc_reg16(SP),
+            0x0B => Self::dec_reg16(BC),
+            0x1B => Self::dec_reg16(DE),
+            0x2B => Self::dec_reg16(HL),
+            0x3B => Self::dec_reg16(SP),
+
+            0x04 | 0x0C | 0x14 | 0x1C | 0x24 | 0x2C | 0x34 | 0x3C => Self::inc_reg8(opcode),
+            0x05 | 0x0D | 0x15 | 0x1D | 0x25 | 0x2D | 0x35 | 0x3D => Self::dec_reg8(opcode),
+
+            0x80..=0xBF => Self::alu_reg(opcode),
+            0xC6 | 0xCE | 0xD6 | 0xDE | 0xE6 | 0xEE | 0xF6 | 0xFE => Self::alu_d8(opcode),
+
+            0x09 => Self::add_hl(BC),
+            0x19 => Self::add_hl(DE),
+            0x29 => Self::add_hl(HL),
+            0x39 => Self::add_hl(SP),
+            0xE8 => Self::with(
+                Operation::AddSignedToSP,
+                Target::ImmediateByte,
+                Target::Register(SP),
+                16,
+            ),
+            0xF8 => Self::with(
+                Operation::AddSignedToSP,
+                Target::ImmediateByte,
+                Target::Register(HL),
+                12,
+            ),
+
+            0x07 => Self::no_operand(Operation::Rlca),
+            0x0F => Self::no_operand(Operation::Rrca),
+            0x17 => Self::no_operand(Operation::Rla),
+            0x1F => Self::no_operand(Operation::Rra),
 
-            0x04 => Self::inc_reg8(B),
-            0x0C => Self::inc_reg8(C),
-            0x14 => Self::inc_reg8(D),
-            0x1C => Self::inc_reg8(E),
-            0x24 => Self::inc_reg8(H),
-            0x2C => Self::inc_reg8(L),
+            0x27 => Self::no_operand(Operation::Daa),
+            0x2F => Self::no_operand(Operation::Cpl),
+            0x37 => Self::no_operand(Operation::Scf),
+            0x3F => Self::no_operand(Operation::Ccf),
 
-            0x2F => Self::cpl(),
+            0xF3 => Self::no_operand(Operation::Di),
+            0xFB => Self::no_operand(Operation::Ei),
+            0x76 => Self::no_operand(Operation::Halt),
+            // STOP is followed by a padding byte which is skipped
+            0x10 => Self::with(Operation::Stop, Target::ImmediateByte, Target::None, 4),
 
             0xC1 => Self::pop(BC),
             0xD1 => Self::pop(DE),
             0xE1 => Self::pop(HL),
             0xF1 => Self::pop(AF),
+            0xC5 => Self::push(BC),
+            0xD5 => Self::push(DE),
+            0xE5 => Self::push(HL),
+            0xF5 => Self::push(AF),
 
             0xC2 => Self::jmp(Condition::NonZero),
-            0xC3 => Self::jmp(Condition::None),
+            0xCA => Self::jmp(Condition::Zero),
             0xD2 => Self::jmp(Condition::NoCarry),
+            0xDA => Self::jmp(Condition::Carry),
+            0xC3 => Self::jmp(Condition::None),
+            0xE9 => Self::with(
+                Operation::Jmp(Condition::None),
+                Target::Register(HL),
+                Target::None,
+                4,
+            ),
+
+            0x20 => Self::jmp_relative(Condition::NonZero),
+            0x28 => Self::jmp_relative(Condition::Zero),
+            0x30 => Self::jmp_relative(Condition::NoCarry),
+            0x38 => Self::jmp_relative(Condition::Carry),
+            0x18 => Self::jmp_relative(Condition::None),
+
+            0xC4 => Self::call(Condition::NonZero),
+            0xCC => Self::call(Condition::Zero),
+            0xD4 => Self::call(Condition::NoCarry),
+            0xDC => Self::call(Condition::Carry),
+            0xCD => Self::call(Condition::None),
+
+            0xC0 => Self::ret(Condition::NonZero),
+            0xC8 => Self::ret(Condition::Zero),
+            0xD0 => Self::ret(Condition::NoCarry),
+            0xD8 => Self::ret(Condition::Carry),
+            0xC9 => Self::ret(Condition::None),
+            0xD9 => Self::no_operand_with_cycles(Operation::Reti, 16),
+
+            0xC7 | 0xCF | 0xD7 | 0xDF | 0xE7 | 0xEF | 0xF7 | 0xFF => {
+                Self::no_operand_with_cycles(Operation::Rst((opcode & 0b111000) as u16), 16)
+            }
+
+            Self::PREFIX => panic!("Prefix opcode must be decoded with the following byte"),
 
-            _ => panic!("Opcode non implemented: {:#04x}", opcode),
+            0xD3 | 0xDB | 0xDD | 0xE3 | 0xE4 | 0xEB | 0xEC | 0xED | 0xF4 | 0xFC | 0xFD => {
+                Instruction::default()
+            }
         };
 
         result.opcode = opcode;
         result
     }
 
+    // Decode the byte following the 0xCB prefix
+    fn from_prefixed_opcode(opcode: u8) -> Self {
+        let target = Self::operand_from_bits(opcode);
+        let bit = (opcode >> 3) & 0b111;
+        let op = match opcode {
+            0x00..=0x07 => Operation::Rlc,
+            0x08..=0x0F => Operation::Rrc,
+            0x10..=0x17 => Operation::Rl,
+            0x18..=0x1F => Operation::Rr,
+            0x20..=0x27 => Operation::Sla,
+            0x28..=0x2F => Operation::Sra,
+            0x30..=0x37 => Operation::Swap,
+            0x38..=0x3F => Operation::Srl,
+            0x40..=0x7F => Operation::Bit(bit),
+            0x80..=0xBF => Operation::Res(bit),
+            0xC0..=0xFF => Operation::Set(bit),
+        };
+
+        // BIT only reads its operand, so (HL) costs one memory access less
+        let (dest, clock_cycles) = match (op, Self::is_hl_pointer(&target)) {
+            (Operation::Bit(_), true) => (Target::None, 12),
+            (Operation::Bit(_), false) => (Target::None, 8),
+            (_, true) => (target, 16),
+            (_, false) => (target, 8),
+        };
+
+        Instruction {
+            opcode,
+            op,
+            source: target,
+            dest,
+            clock_cycles,
+            clock_cycles_not_taken: clock_cycles,
+            op_byte_len: 2,
+        }
+    }
+
     // OPCODE HELPERS
-    fn ld_d16(reg: Registers) -> Self {
+    fn with(op: Operation, source: Target, dest: Target, clock_cycles: u8) -> Self {
         Instruction {
             opcode: 0,
-            op: Operation::LD,
-            source: Target::ImmediateWord,
-            dest: Target::Register(reg),
-            clock_cycles: 12,
+            op,
+            source,
+            dest,
+            clock_cycles,
+            clock_cycles_not_taken: clock_cycles,
             op_byte_len: 1,
         }
     }
 
-    // TODO: Write tests to ensure the correct instructions are generated
-    fn ld_d8_reg_to_reg_match(dest: Registers, opcode: u8) -> Self {
-        use Registers::*;
-        match (opcode & 0x0F) % 0x8 {
-            0x0 => Self::ld_d8_reg_to_reg(B, dest),
-            0x1 => Self::ld_d8_reg_to_reg(C, dest),
-            0x2 => Self::ld_d8_reg_to_reg(D, dest),
-            0x3 => Self::ld_d8_reg_to_reg(E, dest),
-            0x4 => Self::ld_d8_reg_to_reg(H, dest),
-            0x5 => Self::ld_d8_reg_to_reg(L, dest),
-            0x6 => Self::ld_d8_from_hl_ptr(dest),
-            0x7 => Self::ld_d8_reg_to_reg(A, dest),
+    // Operand encoded in 3 bits, in the order used by the whole opcode table
+    fn operand_from_bits(bits: u8) -> Target {
+        use HalfRegisters::*;
+        match bits & 0b111 {
+            0x0 => Target::HalfRegister(B),
+            0x1 => Target::HalfRegister(C),
+            0x2 => Target::HalfRegister(D),
+            0x3 => Target::HalfRegister(E),
+            0x4 => Target::HalfRegister(H),
+            0x5 => Target::HalfRegister(L),
+            0x6 => Target::IndirectRegister(Registers::HL),
+            0x7 => Target::HalfRegister(A),
             _ => panic!("SHOULDN'T EVER HAPPEN"),
         }
     }
 
-    fn ld_d8_reg_to_reg(source: Registers, dest: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::LD,
-            source: Target::HalfRegister(source),
-            dest: Target::HalfRegister(dest),
-            clock_cycles: 4,
-            op_byte_len: 1,
-        }
+    fn is_hl_pointer(target: &Target) -> bool {
+        matches!(target, Target::IndirectRegister(Registers::HL))
     }
 
-    fn ld_d8_from_hl_ptr(dest: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::LD,
-            source: Target::IndirectRegister(Registers::HL),
-            dest: Target::HalfRegister(dest),
-            clock_cycles: 8,
-            op_byte_len: 1,
+    fn ld_d16(reg: Registers) -> Self {
+        Self::with(
+            Operation::LD,
+            Target::ImmediateWord,
+            Target::Register(reg),
+            12,
+        )
+    }
+
+    fn ld_a_to_ptr(dest: Target) -> Self {
+        Self::with(
+            Operation::LD,
+            Target::HalfRegister(HalfRegisters::A),
+            dest,
+            8,
+        )
+    }
+
+    fn ld_a_from_ptr(source: Target) -> Self {
+        Self::with(
+            Operation::LD,
+            source,
+            Target::HalfRegister(HalfRegisters::A),
+            8,
+        )
+    }
+
+    fn ld_reg_to_reg(opcode: u8) -> Self {
+        let source = Self::operand_from_bits(opcode);
+        let dest = Self::operand_from_bits(opcode >> 3);
+        let clock_cycles = if Self::is_hl_pointer(&source) || Self::is_hl_pointer(&dest) {
+            8
+        } else {
+            4
+        };
+
+        Self::with(Operation::LD, source, dest, clock_cycles)
+    }
+
+    fn ld_d8_imm(opcode: u8) -> Self {
+        let dest = Self::operand_from_bits(opcode >> 3);
+        let clock_cycles = if Self::is_hl_pointer(&dest) { 12 } else { 8 };
+
+        Self::with(Operation::LD, Target::ImmediateByte, dest, clock_cycles)
+    }
+
+    fn alu_operation(opcode: u8) -> Operation {
+        match (opcode >> 3) & 0b111 {
+            0x0 => Operation::Add,
+            0x1 => Operation::Adc,
+            0x2 => Operation::Sub,
+            0x3 => Operation::Sbc,
+            0x4 => Operation::And,
+            0x5 => Operation::Xor,
+            0x6 => Operation::Or,
+            0x7 => Operation::Cp,
+            _ => panic!("SHOULDN'T EVER HAPPEN"),
         }
     }
 
-    fn ld_d8_imm(dest: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::LD,
-            source: Target::ImmediateByte,
-            dest: Target::HalfRegister(dest),
-            clock_cycles: 8,
-            op_byte_len: 1,
+    fn alu_dest(op: Operation) -> Target {
+        match op {
+            Operation::Cp => Target::None,
+            _ => Target::HalfRegister(HalfRegisters::A),
         }
     }
 
+    fn alu_reg(opcode: u8) -> Self {
+        let op = Self::alu_operation(opcode);
+        let source = Self::operand_from_bits(opcode);
+        let clock_cycles = if Self::is_hl_pointer(&source) { 8 } else { 4 };
+
+        Self::with(op, source, Self::alu_dest(op), clock_cycles)
+    }
+
+    fn alu_d8(opcode: u8) -> Self {
+        let op = Self::alu_operation(opcode);
+
+        Self::with(op, Target::ImmediateByte, Self::alu_dest(op), 8)
+    }
+
+    fn add_hl(reg: Registers) -> Self {
+        Self::with(
+            Operation::AddWord,
+            Target::Register(reg),
+            Target::Register(Registers::HL),
+            8,
+        )
+    }
+
     fn jmp(condition: Condition) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::Jmp(condition),
-            source: Target::ImmediateWord,
-            dest: Target::None,
-            clock_cycles: 16,
-            op_byte_len: 1,
-        }
+        Self::branch(Operation::Jmp(condition), Target::ImmediateWord, 16, 12)
     }
 
-    fn pop(dest: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::Pop,
-            source: Target::None,
-            dest: Target::Register(dest),
-            clock_cycles: 12,
-            op_byte_len: 1,
+    fn jmp_relative(condition: Condition) -> Self {
+        Self::branch(
+            Operation::JmpRelative(condition),
+            Target::ImmediateByte,
+            12,
+            8,
+        )
+    }
+
+    fn call(condition: Condition) -> Self {
+        Self::branch(Operation::Call(condition), Target::ImmediateWord, 24, 12)
+    }
+
+    fn ret(condition: Condition) -> Self {
+        match condition {
+            Condition::None => Self::no_operand_with_cycles(Operation::Ret(condition), 16),
+            // checking the condition costs an extra cycle
+            _ => Self::branch(Operation::Ret(condition), Target::None, 20, 8),
         }
     }
 
-    fn cpl() -> Self {
+    fn branch(op: Operation, source: Target, taken: u8, not_taken: u8) -> Self {
         Instruction {
-            opcode: 0,
-            op: Operation::CPL,
-            source: Target::None,
-            dest: Target::None,
-            clock_cycles: 4,
-            op_byte_len: 1,
+            clock_cycles_not_taken: not_taken,
+            ..Self::with(op, source, Target::None, taken)
         }
     }
 
+    fn pop(dest: Registers) -> Self {
+        Self::with(Operation::Pop, Target::None, Target::Register(dest), 12)
+    }
+
+    fn push(source: Registers) -> Self {
+        Self::with(Operation::Push, Target::Register(source), Target::None, 16)
+    }
+
+    fn no_operand(op: Operation) -> Self {
+        Self::no_operand_with_cycles(op, 4)
+    }
+
+    fn no_operand_with_cycles(op: Operation, clock_cycles: u8) -> Self {
+        Self::with(op, Target::None, Target::None, clock_cycles)
+    }
+
     fn inc_reg16(reg: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::IncWord,
-            source: Target::Register(reg),
-            dest: Target::Register(reg),
-            clock_cycles: 8,
-            op_byte_len: 1,
-        }
+        Self::with(
+            Operation::IncWord,
+            Target::Register(reg),
+            Target::Register(reg),
+            8,
+        )
     }
 
-    fn inc_reg8(reg: Registers) -> Self {
-        Instruction {
-            opcode: 0,
-            op: Operation::IncByte,
-            source: Target::Register(reg),
-            dest: Target::Register(reg),
-            clock_cycles: 4,
-            op_byte_len: 1,
-        }
+    fn dec_reg16(reg: Registers) -> Self {
+        Self::with(
+            Operation::DecWord,
+            Target::Register(reg),
+            Target::Register(reg),
+            8,
+        )
+    }
+
+    fn inc_reg8(opcode: u8) -> Self {
+        let target = Self::operand_from_bits(opcode >> 3);
+        let clock_cycles = if Self::is_hl_pointer(&target) { 12 } else { 4 };
+
+        Self::with(Operation::IncByte, target, target, clock_cycles)
+    }
+
+    fn dec_reg8(opcode: u8) -> Self {
+        let target = Self::operand_from_bits(opcode >> 3);
+        let clock_cycles = if Self::is_hl_pointer(&target) { 12 } else { 4 };
+
+        Self::with(Operation::DecByte, target, target, clock_cycles)
     }
 }
 
 impl Display for Instruction {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.op_byte_len == 2 {
+            write!(f, "[{:#04x} ", Instruction::PREFIX)?;
+        } else {
+            write!(f, "[")?;
+        }
         write!(
             f,
-            "[{:#04x}] {} {} {}",
+            "{:#04x}] {} {} {}",
             self.opcode, self.op, self.dest, self.source
         )
     }
@@ -304,6 +728,7 @@ impl Default for Instruction {
             source: Target::None,
             dest: Target::None,
             clock_cycles: 0,
+            clock_cycles_not_taken: 0,
             op_byte_len: 0,
         }
     }
@@ -311,24 +736,171 @@ impl Default for Instruction {
 
 // INSTRUCTION FUNCTIONS
 fn jmp(cpu: &mut CPU, source: Sized, condition: &Condition) -> Sized {
-    match condition {
-        Condition::None => cpu.pc = source.into(),
-        Condition::NonZero if !cpu.get_flag('z') => cpu.pc = source.into(),
-        Condition::NoCarry if !cpu.get_flag('c') => cpu.pc = source.into(),
-        _ => {}
+    if condition.is_met(cpu) {
+        cpu.pc = source.into();
     }
 
     Sized::Zero
 }
 
-fn pop(bus: &Bus, cpu: &mut CPU) -> Sized {
+fn jmp_relative(cpu: &mut CPU, source: Sized, condition: &Condition) -> Sized {
+    let offset = u8::from(source) as i8;
+    if condition.is_met(cpu) {
+        cpu.pc = cpu.pc.wrapping_add(offset as u16);
+    }
+
+    Sized::Zero
+}
+
+fn call(bus: &mut Bus, cpu: &mut CPU, source: Sized, condition: &Condition) -> Sized {
+    if condition.is_met(cpu) {
+        bus.tick_m_cycle(); // internal delay before the push
+        cpu.push_word_to_stack(bus, cpu.pc);
+        cpu.pc = source.into();
+    }
+
+    Sized::Zero
+}
+
+fn ret(bus: &mut Bus, cpu: &mut CPU, condition: &Condition) -> Sized {
+    if !matches!(condition, Condition::None) {
+        // checking the condition takes a cycle before popping
+        bus.tick_m_cycle();
+    }
+    if condition.is_met(cpu) {
+        cpu.pc = cpu.pop_word_from_stack(bus);
+    }
+
+    Sized::Zero
+}
+
+fn reti(bus: &mut Bus, cpu: &mut CPU) -> Sized {
+    cpu.pc = cpu.pop_word_from_stack(bus);
+    cpu.ime = true;
+
+    Sized::Zero
+}
+
+fn rst(bus: &mut Bus, cpu: &mut CPU, vector: u16) -> Sized {
+    bus.tick_m_cycle(); // internal delay before the push
+    cpu.push_word_to_stack(bus, cpu.pc);
+    cpu.pc = vector;
+
+    Sized::Zero
+}
+
+fn push(bus: &mut Bus, cpu: &mut CPU, source: Sized) -> Sized {
+    bus.tick_m_cycle(); // internal delay before the push
+    cpu.push_word_to_stack(bus, source.into());
+
+    Sized::Zero
+}
+
+fn pop(bus: &mut Bus, cpu: &mut CPU) -> Sized {
     Sized::Word(cpu.pop_word_from_stack(bus))
 }
 
+fn add(cpu: &mut CPU, source: Sized, with_carry: bool) -> Sized {
+    let a = cpu.af.a;
+    let value = u8::from(source);
+    let carry = (with_carry && cpu.get_flag(Flag::Carry)) as u8;
+    let result = a.wrapping_add(value).wrapping_add(carry);
+
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.update_flag(Flag::HalfCarry, (a & 0xF) + (value & 0xF) + carry > 0xF);
+    cpu.update_flag(
+        Flag::Carry,
+        (a as u16) + (value as u16) + (carry as u16) > 0xFF,
+    );
+
+    Sized::Byte(result)
+}
+
+fn sub(cpu: &mut CPU, source: Sized, with_carry: bool) -> Sized {
+    let a = cpu.af.a;
+    let value = u8::from(source);
+    let carry = (with_carry && cpu.get_flag(Flag::Carry)) as u8;
+    let result = a.wrapping_sub(value).wrapping_sub(carry);
+
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.set_flag(Flag::Subtract);
+    cpu.update_flag(Flag::HalfCarry, (a & 0xF) < (value & 0xF) + carry);
+    cpu.update_flag(Flag::Carry, (a as u16) < (value as u16) + (carry as u16));
+
+    Sized::Byte(result)
+}
+
+fn and(cpu: &mut CPU, source: Sized) -> Sized {
+    let result = cpu.af.a & u8::from(source);
+
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.set_flag(Flag::HalfCarry);
+    cpu.clear_flag(Flag::Carry);
+
+    Sized::Byte(result)
+}
+
+fn or(cpu: &mut CPU, source: Sized) -> Sized {
+    let result = cpu.af.a | u8::from(source);
+
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.clear_flag(Flag::Carry);
+
+    Sized::Byte(result)
+}
+
+fn xor(cpu: &mut CPU, source: Sized) -> Sized {
+    let result = cpu.af.a ^ u8::from(source);
+
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.clear_flag(Flag::Carry);
+
+    Sized::Byte(result)
+}
+
+fn cp(cpu: &mut CPU, source: Sized) -> Sized {
+    // compare is a subtraction whose result is discarded
+    sub(cpu, source, false);
+
+    Sized::Zero
+}
+
+fn add_word(cpu: &mut CPU, source: Sized) -> Sized {
+    let hl = cpu.get_register_word(Registers::HL);
+    let value = u16::from(source);
+    let result = hl.wrapping_add(value);
+
+    cpu.clear_flag(Flag::Subtract);
+    cpu.update_flag(Flag::HalfCarry, (hl & 0xFFF) + (value & 0xFFF) > 0xFFF);
+    cpu.update_flag(Flag::Carry, (hl as u32) + (value as u32) > 0xFFFF);
+
+    Sized::Word(result)
+}
+
+fn add_signed_to_sp(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    let sp = cpu.sp;
+    let result = sp.wrapping_add(value as i8 as u16);
+
+    // flags are computed on the low byte, as an unsigned addition
+    cpu.clear_flag(Flag::Zero);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.update_flag(Flag::HalfCarry, (sp & 0xF) + (value as u16 & 0xF) > 0xF);
+    cpu.update_flag(Flag::Carry, (sp & 0xFF) + (value as u16) > 0xFF);
+
+    Sized::Word(result)
+}
+
 fn cpl(cpu: &mut CPU) -> Sized {
     cpu.af.a = !cpu.af.a;
-    cpu.set_flag('h');
-    cpu.set_flag('n');
+    cpu.set_flag(Flag::HalfCarry);
+    cpu.set_flag(Flag::Subtract);
 
     Sized::Zero
 }
@@ -341,10 +913,653 @@ fn inc(cpu: &mut CPU, source: Sized, should_set_flags: bool) -> Sized {
     };
 
     if should_set_flags {
-        cpu.clear_flag('n');
-        cpu.update_flag('z', result.is_value_zero());
-        cpu.update_flag('h', result.check_value_for_half_carry());
+        cpu.clear_flag(Flag::Subtract);
+        cpu.update_flag(Flag::Zero, result.is_value_zero());
+        cpu.update_flag(Flag::HalfCarry, result.check_value_for_half_carry());
+    }
+
+    result
+}
+
+fn dec(cpu: &mut CPU, source: Sized, should_set_flags: bool) -> Sized {
+    let result = match source {
+        Sized::Word(value) => Sized::Word(value.wrapping_sub(1)),
+        Sized::Byte(value) => Sized::Byte(value.wrapping_sub(1)),
+        Sized::Zero => panic!("Calling dec on zero sized value"),
+    };
+
+    if should_set_flags {
+        cpu.set_flag(Flag::Subtract);
+        cpu.update_flag(Flag::Zero, result.is_value_zero());
+        cpu.update_flag(Flag::HalfCarry, u8::from(result) & 0xF == 0xF);
     }
 
     result
 }
+
+fn rlca(cpu: &mut CPU) -> Sized {
+    let a = cpu.af.a;
+    cpu.af.a = a.rotate_left(1);
+    set_rotate_flags(cpu, a & 0x80 != 0);
+
+    Sized::Zero
+}
+
+fn rrca(cpu: &mut CPU) -> Sized {
+    let a = cpu.af.a;
+    cpu.af.a = a.rotate_right(1);
+    set_rotate_flags(cpu, a & 0x1 != 0);
+
+    Sized::Zero
+}
+
+fn rla(cpu: &mut CPU) -> Sized {
+    let a = cpu.af.a;
+    cpu.af.a = (a << 1) | cpu.get_flag(Flag::Carry) as u8;
+    set_rotate_flags(cpu, a & 0x80 != 0);
+
+    Sized::Zero
+}
+
+fn rra(cpu: &mut CPU) -> Sized {
+    let a = cpu.af.a;
+    cpu.af.a = (a >> 1) | ((cpu.get_flag(Flag::Carry) as u8) << 7);
+    set_rotate_flags(cpu, a & 0x1 != 0);
+
+    Sized::Zero
+}
+
+// Accumulator rotations always clear Z, unlike their CB prefixed versions
+fn set_rotate_flags(cpu: &mut CPU, carry: bool) {
+    cpu.clear_flag(Flag::Zero);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.update_flag(Flag::Carry, carry);
+}
+
+fn daa(cpu: &mut CPU) -> Sized {
+    let mut a = cpu.af.a;
+    let mut carry = cpu.get_flag(Flag::Carry);
+
+    if !cpu.get_flag(Flag::Subtract) {
+        if carry || a > 0x99 {
+            a = a.wrapping_add(0x60);
+            carry = true;
+        }
+        if cpu.get_flag(Flag::HalfCarry) || (a & 0xF) > 0x9 {
+            a = a.wrapping_add(0x6);
+        }
+    } else {
+        if carry {
+            a = a.wrapping_sub(0x60);
+        }
+        if cpu.get_flag(Flag::HalfCarry) {
+            a = a.wrapping_sub(0x6);
+        }
+    }
+
+    cpu.af.a = a;
+    cpu.update_flag(Flag::Zero, a == 0);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.update_flag(Flag::Carry, carry);
+
+    Sized::Zero
+}
+
+fn scf(cpu: &mut CPU) -> Sized {
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.set_flag(Flag::Carry);
+
+    Sized::Zero
+}
+
+fn ccf(cpu: &mut CPU) -> Sized {
+    let carry = cpu.get_flag(Flag::Carry);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.update_flag(Flag::Carry, !carry);
+
+    Sized::Zero
+}
+
+fn di(cpu: &mut CPU) -> Sized {
+    cpu.ime = false;
+    cpu.ime_scheduled = false;
+
+    Sized::Zero
+}
+
+fn ei(cpu: &mut CPU) -> Sized {
+    cpu.ime_scheduled = true;
+
+    Sized::Zero
+}
+
+fn halt(bus: &Bus, cpu: &mut CPU) -> Sized {
+    // with an interrupt already pending, HALT exits right away. Right after EI
+    // the interrupt is dispatched and returns to the HALT, which runs again.
+    // With IME cleared, the next opcode is read twice
+    let pending = cpu.has_pending_interrupt(bus);
+    if pending && cpu.ime_scheduled {
+        cpu.pc = cpu.pc.wrapping_sub(1);
+    } else if pending && !cpu.ime {
+        cpu.halt_bug = true;
+    } else {
+        cpu.halted = true;
+    }
+
+    Sized::Zero
+}
+
+fn lock(cpu: &mut CPU) -> Sized {
+    cpu.locked = true;
+
+    Sized::Zero
+}
+
+fn stop(bus: &mut Bus, cpu: &mut CPU) -> Sized {
+    // the divider is reset as the clock stops
+    bus.reset_divider();
+    cpu.stopped = true;
+
+    Sized::Zero
+}
+
+fn rlc(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    set_shift_flags(cpu, value.rotate_left(1), value & 0x80 != 0)
+}
+
+fn rrc(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    set_shift_flags(cpu, value.rotate_right(1), value & 0x1 != 0)
+}
+
+fn rl(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    let result = (value << 1) | cpu.get_flag(Flag::Carry) as u8;
+    set_shift_flags(cpu, result, value & 0x80 != 0)
+}
+
+fn rr(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    let result = (value >> 1) | ((cpu.get_flag(Flag::Carry) as u8) << 7);
+    set_shift_flags(cpu, result, value & 0x1 != 0)
+}
+
+fn sla(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    set_shift_flags(cpu, value << 1, value & 0x80 != 0)
+}
+
+fn sra(cpu: &mut CPU, source: Sized) -> Sized {
+    // arithmetic shift keeps the sign bit
+    let value = u8::from(source);
+    set_shift_flags(cpu, (value >> 1) | (value & 0x80), value & 0x1 != 0)
+}
+
+fn srl(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    set_shift_flags(cpu, value >> 1, value & 0x1 != 0)
+}
+
+fn swap(cpu: &mut CPU, source: Sized) -> Sized {
+    let value = u8::from(source);
+    set_shift_flags(cpu, value.rotate_left(4), false)
+}
+
+fn set_shift_flags(cpu: &mut CPU, result: u8, carry: bool) -> Sized {
+    cpu.update_flag(Flag::Zero, result == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.clear_flag(Flag::HalfCarry);
+    cpu.update_flag(Flag::Carry, carry);
+
+    Sized::Byte(result)
+}
+
+fn test_bit(cpu: &mut CPU, source: Sized, bit: u8) -> Sized {
+    cpu.update_flag(Flag::Zero, u8::from(source) & (1 << bit) == 0);
+    cpu.clear_flag(Flag::Subtract);
+    cpu.set_flag(Flag::HalfCarry);
+
+    Sized::Zero
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::model::Model;
+
+    // Mnemonic, taken and not taken cycles, and length with the immediate bytes
+    fn describe(instruction: &Instruction) -> (String, u8, u8, u16) {
+        let text = instruction.to_string();
+        let text = text[text.find("] ").unwrap() + 2..]
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let immediate_size = instruction
+            .source
+            .immediate_size()
+            .max(instruction.dest.immediate_size());
+        (
+            text,
+            instruction.clock_cycles,
+            instruction.clock_cycles_not_taken,
+            instruction.op_byte_len + immediate_size,
+        )
+    }
+
+    fn check(decoded: &[(Instruction, &str, u8, u8, u16)]) {
+        for (instruction, text, taken, not_taken, length) in decoded {
+            assert_eq!(
+                describe(instruction),
+                (text.to_string(), *taken, *not_taken, *length),
+                "opcode {:#04x}",
+                instruction.opcode
+            );
+        }
+    }
+
+    #[test]
+    fn decodes_unprefixed_opcodes() {
+        let decoded: Vec<_> = [
+            (0x00, "NOP", 4, 4, 1),
+            (0x01, "LD BC XXXX", 12, 12, 3),
+            (0x02, "LD (BC) A", 8, 8, 1),
+            (0x08, "LD (XXXX) SP", 20, 20, 3),
+            (0x10, "STOP XX", 4, 4, 2),
+            (0x18, "JR XX", 12, 8, 2),
+            (0x20, "JR NZ XX", 12, 8, 2),
+            (0x22, "LD (HL+) A", 8, 8, 1),
+            (0x27, "DAA", 4, 4, 1),
+            (0x2F, "CPL", 4, 4, 1),
+            (0x34, "INC (HL) (HL)", 12, 12, 1),
+            (0x36, "LD (HL) XX", 12, 12, 2),
+            (0x39, "ADD HL SP", 8, 8, 1),
+            (0x3A, "LD A (HL-)", 8, 8, 1),
+            (0x3D, "DEC A A", 4, 4, 1),
+            (0x41, "LD B C", 4, 4, 1),
+            (0x46, "LD B (HL)", 8, 8, 1),
+            (0x70, "LD (HL) B", 8, 8, 1),
+            (0x76, "HALT", 4, 4, 1),
+            (0x7F, "LD A A", 4, 4, 1),
+            (0x86, "ADD A (HL)", 8, 8, 1),
+            (0x9F, "SBC A A", 4, 4, 1),
+            (0xAF, "XOR A A", 4, 4, 1),
+            (0xBE, "CP (HL)", 8, 8, 1),
+            (0xC0, "RET NZ", 20, 8, 1),
+            (0xC2, "JP NZ XXXX", 16, 12, 3),
+            (0xC4, "CALL NZ XXXX", 24, 12, 3),
+            (0xC9, "RET", 16, 16, 1),
+            (0xCD, "CALL XXXX", 24, 12, 3),
+            (0xD9, "RETI", 16, 16, 1),
+            (0xDE, "SBC A XX", 8, 8, 2),
+            (0xE0, "LD (0xFFXX) A", 12, 12, 2),
+            (0xE2, "LD (0xFF00+C) A", 8, 8, 1),
+            (0xE8, "ADD SP XX", 16, 16, 2),
+            (0xE9, "JP HL", 4, 4, 1),
+            (0xF1, "POP AF", 12, 12, 1),
+            (0xF5, "PUSH AF", 16, 16, 1),
+            (0xF8, "ADD HL XX", 12, 12, 2),
+            (0xF9, "LD SP HL", 8, 8, 1),
+            (0xFA, "LD A (XXXX)", 16, 16, 3),
+            (0xFE, "CP XX", 8, 8, 2),
+            (0xFF, "RST 0x38", 16, 16, 1),
+        ]
+        .iter()
+        .map(|(opcode, text, taken, not_taken, length)| {
+            (
+                Instruction::from_opcode(*opcode),
+                *text,
+                *taken,
+                *not_taken,
+                *length,
+            )
+        })
+        .collect();
+        check(&decoded);
+    }
+
+    #[test]
+    fn decodes_prefixed_opcodes() {
+        let decoded: Vec<_> = [
+            (0x00, "RLC B B", 8),
+            (0x06, "RLC (HL) (HL)", 16),
+            (0x0B, "RRC E E", 8),
+            (0x11, "RL C C", 8),
+            (0x1F, "RR A A", 8),
+            (0x24, "SLA H H", 8),
+            (0x2D, "SRA L L", 8),
+            (0x37, "SWAP A A", 8),
+            (0x3E, "SRL (HL) (HL)", 16),
+            (0x46, "BIT 0, (HL)", 12),
+            (0x7F, "BIT 7, A", 8),
+            (0x87, "RES 0, A A", 8),
+            (0xB6, "RES 6, (HL) (HL)", 16),
+            (0xC1, "SET 0, C C", 8),
+            (0xFE, "SET 7, (HL) (HL)", 16),
+        ]
+        .iter()
+        .map(|(opcode, text, cycles)| {
+            (
+                Instruction::from_prefixed_opcode(*opcode),
+                *text,
+                *cycles,
+                *cycles,
+                2,
+            )
+        })
+        .collect();
+        check(&decoded);
+    }
+
+    #[test]
+    fn rejects_illegal_opcodes() {
+        let illegal = [
+            0xD3, 0xDB, 0xDD, 0xE3, 0xE4, 0xEB, 0xEC, 0xED, 0xF4, 0xFC, 0xFD,
+        ];
+        for opcode in (0x00..=0xFF).filter(|opcode| *opcode != Instruction::PREFIX) {
+            let instruction = Instruction::from_opcode(opcode);
+            assert_eq!(
+                matches!(instruction.op, Operation::Illegal),
+                illegal.contains(&opcode),
+                "opcode {:#04x}",
+                opcode
+            );
+        }
+    }
+
+    fn cpu_with(a: u8, flags: u8) -> CPU {
+        let mut af = AFRegister::new();
+        af.set_word(((a as u16) << 8) | flags as u16);
+        CPU {
+            af,
+            bc: Register::new(),
+            de: Register::new(),
+            hl: Register::new(),
+            sp: 0,
+            pc: 0,
+            stopped: false,
+            halted: false,
+            halt_bug: false,
+            locked: false,
+            ime: false,
+            ime_scheduled: false,
+        }
+    }
+
+    const Z: u8 = 0b10000000;
+    const N: u8 = 0b1000000;
+    const H: u8 = 0b100000;
+    const C: u8 = 0b10000;
+
+    // Run an 8 bits ALU operation on A and a value, returns A and F afterwards
+    fn alu(op: Operation, a: u8, flags: u8, value: u8) -> (u8, u8) {
+        let mut cpu = cpu_with(a, flags);
+        let result = match op {
+            Operation::Add => add(&mut cpu, Sized::Byte(value), false),
+            Operation::Adc => add(&mut cpu, Sized::Byte(value), true),
+            Operation::Sub => sub(&mut cpu, Sized::Byte(value), false),
+            Operation::Sbc => sub(&mut cpu, Sized::Byte(value), true),
+            Operation::Cp => cp(&mut cpu, Sized::Byte(value)),
+            _ => unreachable!(),
+        };
+        if let Sized::Byte(result) = result {
+            cpu.af.a = result;
+        }
+        (cpu.af.a, cpu.af.flags.to_byte())
+    }
+
+    #[test]
+    fn sets_flags_of_additions() {
+        for (op, a, carry, value, result, flags) in &[
+            (Operation::Add, 0x12, 0, 0x34, 0x46, 0),
+            (Operation::Add, 0x0F, 0, 0x01, 0x10, H),
+            (Operation::Add, 0xF0, 0, 0x10, 0x00, Z | C),
+            (Operation::Add, 0x3A, 0, 0xC6, 0x00, Z | H | C),
+            (Operation::Add, 0x12, C, 0x34, 0x46, 0),
+            (Operation::Adc, 0x12, 0, 0x34, 0x46, 0),
+            (Operation::Adc, 0x0F, C, 0x00, 0x10, H),
+            (Operation::Adc, 0xFF, C, 0x00, 0x00, Z | H | C),
+            (Operation::Adc, 0xE1, C, 0x1E, 0x00, Z | H | C),
+            (Operation::Adc, 0x80, C, 0x7F, 0x00, Z | H | C),
+        ] {
+            assert_eq!(
+                alu(*op, *a, *carry, *value),
+                (*result, *flags),
+                "{} {:#04x} {:#04x} carry {}",
+                op,
+                a,
+                value,
+                carry
+            );
+        }
+    }
+
+    #[test]
+    fn sets_flags_of_subtractions() {
+        for (op, a, carry, value, result, flags) in &[
+            (Operation::Sub, 0x3E, 0, 0x3E, 0x00, Z | N),
+            (Operation::Sub, 0x3E, 0, 0x0F, 0x2F, N | H),
+            (Operation::Sub, 0x3E, 0, 0x40, 0xFE, N | C),
+            (Operation::Sub, 0x3E, C, 0x01, 0x3D, N),
+            (Operation::Sbc, 0x3B, C, 0x2A, 0x10, N),
+            (Operation::Sbc, 0x3B, C, 0x4F, 0xEB, N | H | C),
+            (Operation::Sbc, 0x10, C, 0x0F, 0x00, Z | N | H),
+            (Operation::Sbc, 0x00, C, 0xFF, 0x00, Z | N | H | C),
+            (Operation::Cp, 0x3C, 0, 0x2F, 0x3C, N | H),
+            (Operation::Cp, 0x3C, 0, 0x40, 0x3C, N | C),
+        ] {
+            assert_eq!(
+                alu(*op, *a, *carry, *value),
+                (*result, *flags),
+                "{} {:#04x} {:#04x} carry {}",
+                op,
+                a,
+                value,
+                carry
+            );
+        }
+    }
+
+    #[test]
+    fn sets_flags_of_word_additions() {
+        // ADD HL,rr leaves Z alone and carries out of bits 11 and 15
+        for (hl, value, result, flags) in &[
+            (0x1234, 0x1111, 0x2345, Z),
+            (0x0FFF, 0x0001, 0x1000, Z | H),
+            (0x8A23, 0x0605, 0x9028, Z | H),
+            (0xFFFF, 0x0001, 0x0000, Z | H | C),
+        ] {
+            let mut cpu = cpu_with(0, Z | N);
+            cpu.set_register(Registers::HL, *hl);
+            let sum = u16::from(add_word(&mut cpu, Sized::Word(*value)));
+            assert_eq!((sum, cpu.af.flags.to_byte()), (*result, *flags));
+        }
+
+        // ADD SP,e and LD HL,SP+e carry out of bits 3 and 7 of the unsigned low byte
+        for (sp, offset, result, flags) in &[
+            (0x0000, 0x01, 0x0001, 0),
+            (0x0000, 0xFF, 0xFFFF, 0),
+            (0x0005, 0xFF, 0x0004, H | C),
+            (0xFFF8, 0x08, 0x0000, H | C),
+            (0x00FF, 0x01, 0x0100, H | C),
+            (0x1000, 0x80, 0x0F80, 0),
+        ] {
+            let mut cpu = cpu_with(0, Z | N);
+            cpu.sp = *sp;
+            let sum = u16::from(add_signed_to_sp(&mut cpu, Sized::Byte(*offset)));
+            assert_eq!((sum, cpu.af.flags.to_byte()), (*result, *flags));
+        }
+    }
+
+    #[test]
+    fn adjusts_bcd_after_additions_and_subtractions() {
+        for (op, a, value, result, flags) in &[
+            (Operation::Add, 0x15, 0x27, 0x42, 0),
+            (Operation::Add, 0x09, 0x08, 0x17, 0),
+            (Operation::Add, 0x58, 0x46, 0x04, C),
+            (Operation::Add, 0x99, 0x01, 0x00, Z | C),
+            (Operation::Add, 0x90, 0x90, 0x80, C),
+            (Operation::Sub, 0x42, 0x15, 0x27, N),
+            (Operation::Sub, 0x10, 0x01, 0x09, N),
+            (Operation::Sub, 0x25, 0x25, 0x00, Z | N),
+            (Operation::Sub, 0x00, 0x01, 0x99, N | C),
+        ] {
+            let (sum, flags_after_op) = alu(*op, *a, 0, *value);
+            let mut cpu = cpu_with(sum, flags_after_op);
+            daa(&mut cpu);
+            assert_eq!(
+                (cpu.af.a, cpu.af.flags.to_byte()),
+                (*result, *flags),
+                "{} {:#04x} {:#04x}",
+                op,
+                a,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn sets_half_carry_of_increments() {
+        // the carry is left alone
+        for (value, inc_result, inc_flags, dec_result, dec_flags) in &[
+            (0x0F, 0x10, H | C, 0x0E, N | C),
+            (0xFF, 0x00, Z | H | C, 0xFE, N | C),
+            (0x10, 0x11, C, 0x0F, N | H | C),
+            (0x01, 0x02, C, 0x00, Z | N | C),
+        ] {
+            let mut cpu = cpu_with(0, C);
+            let result = u8::from(inc(&mut cpu, Sized::Byte(*value), true));
+            assert_eq!((result, cpu.af.flags.to_byte()), (*inc_result, *inc_flags));
+
+            let mut cpu = cpu_with(0, C);
+            let result = u8::from(dec(&mut cpu, Sized::Byte(*value), true));
+            assert_eq!((result, cpu.af.flags.to_byte()), (*dec_result, *dec_flags));
+        }
+    }
+
+    // Bus with a program at the start of WRAM, where the instruction helpers run it
+    fn bus_with(program: &[u8]) -> Bus {
+        let mut bus = Bus::from_cartridge(vec![0; 0x8000], Model::DMG).unwrap();
+        for (offset, &byte) in program.iter().enumerate() {
+            bus.set_byte(0xC000 + offset as u16, byte);
+        }
+        bus
+    }
+
+    // Run the instruction at the start of WRAM, returns the cycles it reported
+    fn execute(cpu: &mut CPU, bus: &mut Bus) -> u8 {
+        cpu.pc = 0xC000;
+        Instruction::fetch_new(bus, cpu).execute(bus, cpu)
+    }
+
+    // Cycles, PC and SP after a conditional branch run with the given flags
+    fn branch(program: &[u8], flags: u8) -> (u8, u16, u16) {
+        let mut cpu = cpu_with(0, flags);
+        let mut bus = bus_with(program);
+        // return address for RET
+        bus.set_byte(0xDFF0, 0x34);
+        bus.set_byte(0xDFF1, 0x12);
+        cpu.sp = 0xDFF0;
+        let cycles = execute(&mut cpu, &mut bus);
+        (cycles, cpu.pc, cpu.sp)
+    }
+
+    #[test]
+    fn times_conditional_jumps() {
+        // JP NZ,0x4000
+        assert_eq!(branch(&[0xC2, 0x00, 0x40], 0), (16, 0x4000, 0xDFF0));
+        assert_eq!(branch(&[0xC2, 0x00, 0x40], Z), (12, 0xC003, 0xDFF0));
+        // JR C,-2
+        assert_eq!(branch(&[0x38, 0xFE], C), (12, 0xC000, 0xDFF0));
+        assert_eq!(branch(&[0x38, 0xFE], 0), (8, 0xC002, 0xDFF0));
+    }
+
+    #[test]
+    fn times_conditional_calls_and_returns() {
+        // CALL Z,0x4000
+        assert_eq!(branch(&[0xCC, 0x00, 0x40], Z), (24, 0x4000, 0xDFEE));
+        assert_eq!(branch(&[0xCC, 0x00, 0x40], 0), (12, 0xC003, 0xDFF0));
+        // RET NC
+        assert_eq!(branch(&[0xD0], 0), (20, 0x1234, 0xDFF2));
+        assert_eq!(branch(&[0xD0], C), (8, 0xC001, 0xDFF0));
+    }
+
+    #[test]
+    fn pushes_the_return_address_of_taken_calls() {
+        let mut cpu = cpu_with(0, Z);
+        let mut bus = bus_with(&[0xCC, 0x00, 0x40]);
+        cpu.sp = 0xDFF0;
+        execute(&mut cpu, &mut bus);
+        assert_eq!(bus.fetch_byte(0xDFEE), 0x03);
+        assert_eq!(bus.fetch_byte(0xDFEF), 0xC0);
+    }
+
+    // Run a prefixed opcode on B, returns B and F afterwards
+    fn prefixed(opcode: u8, b: u8, flags: u8) -> (u8, u8) {
+        let mut cpu = cpu_with(0, flags);
+        let mut bus = bus_with(&[Instruction::PREFIX, opcode]);
+        cpu.set_half_register(HalfRegisters::B, b);
+        assert_eq!(execute(&mut cpu, &mut bus), 8);
+        assert_eq!(cpu.pc, 0xC002);
+        (
+            cpu.get_register_byte(HalfRegisters::B),
+            cpu.af.flags.to_byte(),
+        )
+    }
+
+    #[test]
+    fn rotates_and_shifts_through_the_carry() {
+        for (opcode, b, flags, result, result_flags) in &[
+            (0x00, 0x85, 0, 0x0B, C),     // RLC
+            (0x08, 0x01, 0, 0x80, C),     // RRC
+            (0x10, 0x80, 0, 0x00, Z | C), // RL
+            (0x10, 0x11, C, 0x23, 0),     // RL
+            (0x18, 0x01, 0, 0x00, Z | C), // RR
+            (0x18, 0x10, C, 0x88, 0),     // RR
+            (0x20, 0xFF, N | H, 0xFE, C), // SLA
+            (0x28, 0x8A, C, 0xC5, 0),     // SRA
+            (0x28, 0x01, 0, 0x00, Z | C), // SRA
+            (0x38, 0xFF, 0, 0x7F, C),     // SRL
+            (0x30, 0xF1, 0, 0x1F, 0),     // SWAP
+            (0x30, 0x00, C, 0x00, Z),     // SWAP
+        ] {
+            assert_eq!(
+                prefixed(*opcode, *b, *flags),
+                (*result, *result_flags),
+                "opcode {:#04x} on {:#04x}",
+                opcode,
+                b
+            );
+        }
+    }
+
+    #[test]
+    fn tests_bits_without_touching_the_carry() {
+        // BIT 7,B
+        assert_eq!(prefixed(0x78, 0x7F, N | C), (0x7F, Z | H | C));
+        assert_eq!(prefixed(0x78, 0x80, Z), (0x80, H));
+        // BIT 0,B
+        assert_eq!(prefixed(0x40, 0xFE, 0), (0xFE, Z | H));
+    }
+
+    #[test]
+    fn resets_and_sets_bits_through_the_bus() {
+        for (opcode, value, result) in &[
+            (0x86, 0xFF, 0xFE), // RES 0,(HL)
+            (0xBE, 0xFF, 0x7F), // RES 7,(HL)
+            (0xC6, 0x00, 0x01), // SET 0,(HL)
+            (0xFE, 0x00, 0x80), // SET 7,(HL)
+        ] {
+            let mut cpu = cpu_with(0, Z | C);
+            let mut bus = bus_with(&[Instruction::PREFIX, *opcode]);
+            cpu.set_register(Registers::HL, 0xC100);
+            bus.set_byte(0xC100, *value);
+            assert_eq!(execute(&mut cpu, &mut bus), 16);
+            assert_eq!(bus.fetch_byte(0xC100), *result, "opcode {:#04x}", opcode);
+            assert_eq!(cpu.af.flags.to_byte(), Z | C);
+        }
+    }
+}
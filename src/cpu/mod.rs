@@ -1,3 +1,4 @@
+#[allow(clippy::module_inception)]
 pub mod cpu;
 pub mod instructions;
 pub mod registers;
@@ -1,11 +1,62 @@
+use derive_more::Display;
+
+// 16 bits registers, either register pairs or the stack pointer
+#[derive(Debug, Display, Clone, Copy)]
+pub enum Registers {
+    AF,
+    BC,
+    DE,
+    HL,
+    SP,
+}
+
+impl Registers {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::AF => "AF",
+            Self::BC => "BC",
+            Self::DE => "DE",
+            Self::HL => "HL",
+            Self::SP => "SP",
+        }
+    }
+}
+
+// 8 bits registers, each one is the high or low byte of a register pair
+#[derive(Debug, Display, Clone, Copy)]
+pub enum HalfRegisters {
+    A,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+}
+
+impl HalfRegisters {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::A => "A",
+            Self::B => "B",
+            Self::C => "C",
+            Self::D => "D",
+            Self::E => "E",
+            Self::H => "H",
+            Self::L => "L",
+        }
+    }
+}
+
+// Register pair, the first register of the pair (B in BC) is the high byte
 pub struct Register {
-    pub low: u8,
     pub high: u8,
+    pub low: u8,
 }
 
 impl Register {
     pub fn new() -> Self {
-        Register { low: 0, high: 0 }
+        Register { high: 0, low: 0 }
     }
 
     pub fn get_combined(&self) -> u16 {
@@ -41,7 +92,7 @@ impl AFRegister {
     }
 
     fn set_low(&mut self, data: u8) {
-        self.flags.from_byte(data);
+        self.flags.set_byte(data);
     }
 
     fn set_high(&mut self, data: u8) {
@@ -58,87 +109,80 @@ impl AFRegister {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum Flag {
+    Zero,
+    Subtract,
+    HalfCarry,
+    Carry,
+}
+
+impl Flag {
+    fn mask(&self) -> u8 {
+        match self {
+            Flag::Zero => 0b10000000,
+            Flag::Subtract => 0b1000000,
+            Flag::HalfCarry => 0b100000,
+            Flag::Carry => 0b10000,
+        }
+    }
+}
+
+// F register, only the upper nibble exists, the lower one always reads as 0
 pub struct FlagRegister {
-    zero_flag: bool,
-    carry_flag: bool,
-    n_flag: bool,
-    h_flag: bool,
+    value: u8,
 }
 
 impl FlagRegister {
+    const USED_BITS: u8 = 0b11110000;
+
     pub fn new() -> Self {
-        FlagRegister {
-            zero_flag: false,
-            carry_flag: false,
-            n_flag: false,
-            h_flag: false,
-        }
+        FlagRegister { value: 0 }
     }
 
-    pub fn get(&self, c: char) -> bool {
-        match c {
-            'z' => self.zero_flag,
-            'n' => self.n_flag,
-            'h' => self.h_flag,
-            'c' => self.carry_flag,
-            _ => {
-                panic!("Invalid flag character : {}", c);
-            }
-        }
+    pub fn get(&self, flag: Flag) -> bool {
+        self.value & flag.mask() != 0
     }
 
-    pub fn set(&mut self, c: char) {
-        match c {
-            'z' => self.zero_flag = true,
-            'n' => self.n_flag = true,
-            'h' => self.h_flag = true,
-            'c' => self.carry_flag = true,
-            _ => panic!("Invalid flag character : {}", c),
-        };
+    pub fn set(&mut self, flag: Flag) {
+        self.value |= flag.mask();
     }
 
-    pub fn clear(&mut self, c: char) {
-        match c {
-            'z' => self.zero_flag = false,
-            'n' => self.n_flag = false,
-            'h' => self.h_flag = false,
-            'c' => self.carry_flag = false,
-            _ => panic!("Invalid flag character : {}", c),
-        };
+    pub fn clear(&mut self, flag: Flag) {
+        self.value &= !flag.mask();
     }
 
-    pub fn update(&mut self, c: char, val: bool) {
-        if val == true {
-            self.set(c);
+    pub fn update(&mut self, flag: Flag, val: bool) {
+        if val {
+            self.set(flag);
         } else {
-            self.clear(c);
+            self.clear(flag);
         }
     }
 
-    pub fn from_byte(&mut self, data: u8) {
-        let masked = (data & 0b11110000) >> 4;
-        self.zero_flag = masked & 0b1000 != 0;
-        self.n_flag = masked & 0b100 != 0;
-        self.h_flag = masked & 0b10 != 0;
-        self.carry_flag = masked & 0b1 != 0;
+    pub fn set_byte(&mut self, data: u8) {
+        self.value = data & FlagRegister::USED_BITS;
     }
 
     pub fn to_byte(&self) -> u8 {
-        let mut result = 0;
-
-        if self.zero_flag == true {
-            result |= 0b10000000;
-        };
-        if self.n_flag == true {
-            result |= 0b1000000;
-        }
-        if self.h_flag == true {
-            result |= 0b100000;
-        }
-        if self.carry_flag == true {
-            result |= 0b10000;
-        }
+        self.value
+    }
+}
 
-        result
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_the_upper_nibble_of_flags() {
+        let mut af = AFRegister::new();
+        af.set_word(0x12FF);
+        assert_eq!(af.get_combined(), 0x12F0);
+
+        af.flags.clear(Flag::Subtract);
+        af.flags.update(Flag::Carry, false);
+        assert_eq!(af.flags.to_byte(), 0b10100000);
+        assert!(af.flags.get(Flag::Zero) && af.flags.get(Flag::HalfCarry));
+        assert!(!af.flags.get(Flag::Subtract) && !af.flags.get(Flag::Carry));
     }
 }
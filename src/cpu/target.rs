@@ -4,68 +4,116 @@ use std::fmt::{Display, Formatter};
 use crate::bus::*;
 
 use super::cpu::*;
+use super::registers::*;
 use super::sized::*;
 
 #[derive(Clone, Copy)]
 pub enum Target {
-    HalfRegister(Registers),
+    HalfRegister(HalfRegisters),
     Register(Registers),
     ImmediateByte,
     ImmediateWord,
     IndirectRegister(Registers),
+    IndirectRegisterIncrement(Registers), // (HL+)
+    IndirectRegisterDecrement(Registers), // (HL-)
     IndirectImmediate,
     IndirectIOPort,
+    IndirectIOPortRegister(HalfRegisters), // (0xFF00 + C)
     None,
 }
 
 impl Target {
-    pub fn fetch(self, bus: &Bus, cpu: &CPU) -> Sized {
+    // Number of bytes following the opcode needed to resolve this target
+    pub fn immediate_size(&self) -> u16 {
+        match self {
+            Target::ImmediateByte | Target::IndirectIOPort => 1,
+            Target::ImmediateWord | Target::IndirectImmediate => 2,
+            _ => 0,
+        }
+    }
+
+    pub fn fetch(self, bus: &mut Bus, cpu: &mut CPU, immediate: Sized) -> Sized {
         match self {
             Target::HalfRegister(reg) => cpu.get_register_byte(reg).into(),
             Target::Register(reg) => cpu.get_register_word(reg).into(),
-            Target::ImmediateByte => cpu.peek_bus_byte(bus).into(),
-            Target::ImmediateWord => cpu.peek_bus_word(bus).into(),
-            Target::IndirectRegister(reg) => bus.fetch_byte(cpu.get_register_word(reg)).into(),
-            Target::IndirectImmediate => bus.fetch_byte(cpu.peek_bus_word(bus)).into(),
-            Target::IndirectIOPort => bus
-                .fetch_byte((cpu.peek_bus_byte(bus) as u16) + 0xFF00)
+            Target::ImmediateByte | Target::ImmediateWord => immediate,
+            Target::IndirectRegister(reg) => bus.read_cycle(cpu.get_register_word(reg)).into(),
+            Target::IndirectRegisterIncrement(reg) => {
+                let address = cpu.get_register_word(reg);
+                cpu.set_register(reg, address.wrapping_add(1));
+                bus.read_cycle(address).into()
+            }
+            Target::IndirectRegisterDecrement(reg) => {
+                let address = cpu.get_register_word(reg);
+                cpu.set_register(reg, address.wrapping_sub(1));
+                bus.read_cycle(address).into()
+            }
+            Target::IndirectImmediate => bus.read_cycle(immediate.into()).into(),
+            Target::IndirectIOPort => bus.read_cycle(u8::from(immediate) as u16 + 0xFF00).into(),
+            Target::IndirectIOPortRegister(reg) => bus
+                .read_cycle(cpu.get_register_byte(reg) as u16 + 0xFF00)
                 .into(),
             Target::None => Sized::Zero,
         }
     }
 
-    pub fn write(self, bus: &mut Bus, cpu: &mut CPU, data: Sized) {
+    pub fn write(self, bus: &mut Bus, cpu: &mut CPU, immediate: Sized, data: Sized) {
         match self {
             Target::HalfRegister(reg) => cpu.set_half_register(reg, data.into()),
             Target::Register(reg) => cpu.set_register(reg, data.into()),
             Target::ImmediateByte | Target::ImmediateWord => {
                 panic!("Immediate target write, doesn't make sense")
             }
-            Target::IndirectRegister(reg) => bus.set_byte(cpu.get_register_word(reg), data.into()),
-            Target::IndirectImmediate => bus.set_byte(cpu.peek_bus_word(bus), data.into()),
+            Target::IndirectRegister(reg) => {
+                Target::write_indirect(bus, cpu.get_register_word(reg), data)
+            }
+            Target::IndirectRegisterIncrement(reg) => {
+                let address = cpu.get_register_word(reg);
+                cpu.set_register(reg, address.wrapping_add(1));
+                Target::write_indirect(bus, address, data);
+            }
+            Target::IndirectRegisterDecrement(reg) => {
+                let address = cpu.get_register_word(reg);
+                cpu.set_register(reg, address.wrapping_sub(1));
+                Target::write_indirect(bus, address, data);
+            }
+            Target::IndirectImmediate => Target::write_indirect(bus, immediate.into(), data),
             Target::IndirectIOPort => {
-                bus.set_byte((cpu.peek_bus_byte(bus) as u16) + 0xFF00, data.into())
+                Target::write_indirect(bus, u8::from(immediate) as u16 + 0xFF00, data)
+            }
+            Target::IndirectIOPortRegister(reg) => {
+                Target::write_indirect(bus, cpu.get_register_byte(reg) as u16 + 0xFF00, data)
             }
             Target::None => {}
         }
     }
+
+    fn write_indirect(bus: &mut Bus, address: u16, data: Sized) {
+        match data {
+            Sized::Byte(value) => bus.write_cycle(address, value),
+            Sized::Word(value) => {
+                bus.write_cycle(address, value as u8);
+                bus.write_cycle(address.wrapping_add(1), (value >> 8) as u8);
+            }
+            Sized::Zero => panic!("Trying to write zero sized value to {:#06x}", address),
+        }
+    }
 }
 
 impl Display for Target {
     fn fmt(&self, f: &mut Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}",
-            match self {
-                Target::HalfRegister(reg) => reg.as_str(),
-                Target::Register(reg) => reg.as_str(),
-                Target::ImmediateByte => "XX",
-                Target::ImmediateWord => "XXXX",
-                Target::IndirectRegister(reg) => reg.as_str(),
-                Target::IndirectImmediate => "(XXXX)",
-                Target::IndirectIOPort => "0xFFXX",
-                Target::None => "",
-            }
-        )
+        match self {
+            Target::HalfRegister(reg) => write!(f, "{}", reg.as_str()),
+            Target::Register(reg) => write!(f, "{}", reg.as_str()),
+            Target::ImmediateByte => write!(f, "XX"),
+            Target::ImmediateWord => write!(f, "XXXX"),
+            Target::IndirectRegister(reg) => write!(f, "({})", reg.as_str()),
+            Target::IndirectRegisterIncrement(reg) => write!(f, "({}+)", reg.as_str()),
+            Target::IndirectRegisterDecrement(reg) => write!(f, "({}-)", reg.as_str()),
+            Target::IndirectImmediate => write!(f, "(XXXX)"),
+            Target::IndirectIOPort => write!(f, "(0xFFXX)"),
+            Target::IndirectIOPortRegister(reg) => write!(f, "(0xFF00+{})", reg.as_str()),
+            Target::None => Ok(()),
+        }
     }
 }
@@ -0,0 +1,101 @@
+use crate::bus::{Bus, CartridgeError};
+use crate::canvas::Canvas;
+use crate::cpu::cpu::CPU;
+use crate::model::Model;
+
+// The whole console. The CPU drives the clock: every memory access it makes
+// moves the rest of the hardware forward by a M-cycle
+pub struct GameBoy {
+    cpu: CPU,
+    bus: Bus,
+}
+
+impl GameBoy {
+    // Clock cycles needed by the PPU to draw a whole frame
+    pub const FRAME_CYCLES: u64 = 70224;
+
+    pub fn new(
+        rom_path: &str,
+        model: Model,
+        boot_rom: Option<Vec<u8>>,
+    ) -> Result<GameBoy, CartridgeError> {
+        let bus = Bus::new_bus(rom_path, model, boot_rom)?;
+        let cpu = CPU::new_cpu(model, &bus);
+
+        Ok(GameBoy { cpu, bus })
+    }
+
+    // Run a single instruction, or an interrupt dispatch
+    pub fn step(&mut self) {
+        self.cpu.step(&mut self.bus);
+    }
+
+    // Run until the PPU enters VBlank, so that the screen holds a complete
+    // frame. With the LCD off, returns after a frame's worth of cycles instead
+    pub fn run_frame(&mut self) {
+        let start = self.bus.cycles();
+        self.bus.poll_vblank();
+        while !self.bus.poll_vblank() && self.bus.cycles() - start < GameBoy::FRAME_CYCLES {
+            self.step();
+        }
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.bus
+    }
+
+    pub fn screen(&self) -> &Canvas {
+        self.bus.screen()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCDC: u16 = 0xFF40;
+    const LY: u16 = 0xFF44;
+
+    // A cartridge full of NOPs, started where the boot ROM leaves off
+    fn gameboy() -> GameBoy {
+        let bus = Bus::from_cartridge(vec![0; 0x8000], Model::DMG).unwrap();
+        let cpu = CPU::new_cpu(Model::DMG, &bus);
+        GameBoy { cpu, bus }
+    }
+
+    #[test]
+    fn runs_frames_until_vblank_with_the_lcd_on() {
+        let mut gameboy = gameboy();
+        gameboy.run_frame();
+        assert_eq!(gameboy.bus().fetch_byte(LY), 144);
+        // the next frame is a whole PPU frame later, give or take a NOP
+        let start = gameboy.bus().cycles();
+        gameboy.run_frame();
+        assert_eq!(gameboy.bus().fetch_byte(LY), 144);
+        let elapsed = gameboy.bus().cycles() - start;
+        assert!(
+            elapsed.abs_diff(GameBoy::FRAME_CYCLES) < 4,
+            "{} cycles",
+            elapsed
+        );
+    }
+
+    #[test]
+    fn runs_frames_for_a_frame_of_cycles_with_the_lcd_off() {
+        let mut gameboy = gameboy();
+        gameboy.bus_mut().set_byte(LCDC, 0);
+        let start = gameboy.bus().cycles();
+        gameboy.run_frame();
+        let elapsed = gameboy.bus().cycles() - start;
+        assert!(
+            (GameBoy::FRAME_CYCLES..GameBoy::FRAME_CYCLES + 4).contains(&elapsed),
+            "{} cycles",
+            elapsed
+        );
+        assert_eq!(gameboy.bus().fetch_byte(LY), 0);
+    }
+}
@@ -1,9 +1,7 @@
-use minifb::Window;
-
-use crate::bus::Bus;
 use crate::canvas::Canvas;
 use crate::color::Color;
 
+#[derive(Clone, Copy)]
 enum GPUMode {
     HBlank,
     VBlank,
@@ -16,17 +14,33 @@ impl GPUMode {
         match self {
             GPUMode::HBlank => 0,
             GPUMode::VBlank => 1,
-            GPUMode::SearchingOAM => 3,
-            GPUMode::SearchingVRAM => 4,
+            GPUMode::SearchingOAM => 2,
+            GPUMode::SearchingVRAM => 3,
         }
     }
 }
 
+// Interrupts requested by the PPU during a clock cycle
+#[derive(Default)]
+pub struct GPUInterrupts {
+    pub vblank: bool,
+    pub stat: bool,
+}
+
 enum WindowTileMapArea {
     Area9800,
     Area9C00,
 }
 
+impl WindowTileMapArea {
+    pub fn address(&self) -> u16 {
+        match self {
+            WindowTileMapArea::Area9800 => 0x9800,
+            WindowTileMapArea::Area9C00 => 0x9C00,
+        }
+    }
+}
+
 enum BGWindowTileDataArea {
     Area9000, // from -127 to 128, 0x9000 is pattern 0 but tileset starts at 0x8800
     Area8000,
@@ -39,6 +53,15 @@ impl BGWindowTileDataArea {
             BGWindowTileDataArea::Area9000 => 0x9000,
         }
     }
+
+    // Address of a row of pixels of a tile, 2 bytes per row
+    pub fn row_address(&self, tile: u8, row: u8) -> u16 {
+        let offset = match self {
+            BGWindowTileDataArea::Area8000 => 16 * tile as i32,
+            BGWindowTileDataArea::Area9000 => 16 * (tile as i8) as i32,
+        };
+        (self.address() as i32 + offset) as u16 + 2 * row as u16
+    }
 }
 
 enum BGTileMapArea {
@@ -46,6 +69,15 @@ enum BGTileMapArea {
     Area9C00,
 }
 
+impl BGTileMapArea {
+    pub fn address(&self) -> u16 {
+        match self {
+            BGTileMapArea::Area9800 => 0x9800,
+            BGTileMapArea::Area9C00 => 0x9C00,
+        }
+    }
+}
+
 enum OBJSize {
     Size8x8,
     Sixe8x16,
@@ -63,18 +95,14 @@ struct ControlRegister {
 }
 
 impl ControlRegister {
-    const ADDRESS: u16 = 0xFF40;
-
-    pub fn fetch(bus: &Bus) -> Self {
-        let raw = bus.fetch_byte(ControlRegister::ADDRESS);
-
+    pub fn from_byte(raw: u8) -> Self {
         ControlRegister {
-            display_enabled: !((raw & 0b10000000) == 0),
+            display_enabled: raw & 0b10000000 != 0,
             window_tile_map_area: match raw & 0b1000000 {
                 0 => WindowTileMapArea::Area9800,
                 _ => WindowTileMapArea::Area9C00,
             },
-            window_enabled: !((raw & 0b100000) == 0),
+            window_enabled: raw & 0b100000 != 0,
             bg_window_tile_data_area: match raw & 0b10000 {
                 0 => BGWindowTileDataArea::Area9000,
                 _ => BGWindowTileDataArea::Area8000,
@@ -87,280 +115,1059 @@ impl ControlRegister {
                 0 => OBJSize::Size8x8,
                 _ => OBJSize::Sixe8x16,
             },
-            obj_enabled: !((raw & 0b10) == 0),
-            bg_window_enable_priority: !((raw & 0b1) == 0),
+            obj_enabled: raw & 0b10 != 0,
+            bg_window_enable_priority: raw & 0b1 != 0,
         }
     }
 }
 
+// OAM entry selected for the current line
+struct Sprite {
+    y: i32, // screen coordinates of the top left corner
+    x: i32,
+    tile: u8,
+    flags: u8,
+}
+
+impl Sprite {
+    const BEHIND_BG: u8 = 0b10000000;
+    const Y_FLIP: u8 = 0b1000000;
+    const X_FLIP: u8 = 0b100000;
+    const PALETTE_1: u8 = 0b10000;
+}
+
+// Owns the video memory and the LCD registers, the CPU reaches them through the bus
+#[allow(clippy::upper_case_acronyms)]
 pub struct GPU {
-    clock_cycles: u16,
+    vram: Vec<u8>,
+    oam: Vec<u8>,
+    screen: Canvas,
+    line_sprites: Vec<Sprite>, // at most 10, in drawing priority order
+    bg_colors: Vec<u8>,        // color numbers of the background pixels of the line
+    control: u8,
+    stat_interrupts: u8, // STAT bits 3-6, the others are read only
+    scroll_y: u8,
+    scroll_x: u8,
+    y_compare: u8,
+    bg_palette: u8,
+    obj_palette_0: u8,
+    obj_palette_1: u8,
+    window_y: u8,
+    window_x: u8,
+    line_cycles: u16, // clock cycles since the start of the current line
     current_line: u8,
+    window_line: u8, // internal line counter, only moves on lines showing the window
+    window_y_triggered: bool, // LY matched WY during this frame
+    pixel_transfer_clocks: u16,
     mode: GPUMode,
+    stat_mode: GPUMode, // mode read from STAT, lags behind by a clock
+    stat_line: bool, // STAT interrupt sources ORed together, the interrupt fires on its rising edge
+    coincidence: bool, // LY = LYC, frozen while the LCD is off
+    lcd_turned_on: bool,
     stopped: bool,
 }
 
 impl GPU {
     const SCREEN_WIDTH: u8 = 160;
-    const MAX_LINE: u8 = 143; // 144 lines in total
+    const SCREEN_HEIGHT: u8 = 144;
     const LINE_VBLANK_END: u8 = 153;
 
+    const VRAM: u16 = 0x8000;
+    const VRAM_SIZE: usize = 0x2000;
     const TILESET_1: u16 = 0x8000;
-    const BG_MAP_1: u16 = 0x9800;
-    //const BG_MAP_2: u16 = 0x9C00;
-    const OAM: u16 = 0xFE00;
-    const BG_PALETTE: u16 = 0xFF47;
+    pub const OAM: u16 = 0xFE00;
+    pub const OAM_SIZE: usize = 0xA0;
+
+    pub const CONTROL_REGISTER: u16 = 0xFF40;
     const STATUS_REGISTER: u16 = 0xFF41;
     const SCROLL_Y: u16 = 0xFF42;
     const SCROLL_X: u16 = 0xFF43;
     const Y_COORDINATE: u16 = 0xFF44;
-    //const Y_COMPARE: u16 = 0xFF45;
-    const DMA_TRANSFER_REGISTER: u16 = 0xFF46;
+    pub const Y_COMPARE: u16 = 0xFF45;
+    pub const BG_PALETTE: u16 = 0xFF47;
+    const OBJ_PALETTE_0: u16 = 0xFF48;
+    const OBJ_PALETTE_1: u16 = 0xFF49;
+    const WINDOW_Y: u16 = 0xFF4A;
+    pub const WINDOW_X: u16 = 0xFF4B;
 
     const OAM_ACCESS_SCANLINE_CLOCKS: u16 = 80;
-    const VRAM_ACCESS_SCANLINE_CLOCKS: u16 = 172;
-    const HORIZONTAL_BLANK_CLOCKS: u16 = 204;
-    const VERTICAL_BLANCK_LINE_CLOCKS: u16 = 456; // single line of vblank ; 10 lines total
+    const VRAM_ACCESS_SCANLINE_CLOCKS: u16 = 172; // shortest pixel transfer
+    const LINE_CLOCKS: u16 = 456; // every line, vblank ones included
+    const WINDOW_X_OFFSET: u8 = 7;
+    const WINDOW_FETCH_CLOCKS: u16 = 6; // the fetcher restarts with the first window tile
+    const MAX_SPRITES_PER_LINE: usize = 10;
+    const LINE_153_LY_CLOCKS: u16 = 4; // LY reads 0 after that on the last line
+    const LINE_START_CLOCKS: u16 = 4; // the mode lags behind LY by a M-cycle
+    const LY_COMPARE_CLOCKS: u16 = 4; // steps of the comparison on the last line
+
+    // time between turning the LCD on and handing over to the cartridge, DMG0 boot ROM
+    pub const DMG0_BOOT_CLOCKS: u32 = 145 * GPU::LINE_CLOCKS as u32 + 168;
+
+    const HBLANK_INTERRUPT: u8 = 0b1000;
+    const VBLANK_INTERRUPT: u8 = 0b10000;
+    const OAM_INTERRUPT: u8 = 0b100000;
+    const COINCIDENCE_INTERRUPT: u8 = 0b1000000;
 
     pub fn new() -> GPU {
-        GPU {
-            clock_cycles: 0,
+        let mut gpu = GPU {
+            vram: vec![0; GPU::VRAM_SIZE],
+            oam: vec![0; GPU::OAM_SIZE],
+            screen: Canvas::new(GPU::SCREEN_WIDTH as usize, GPU::SCREEN_HEIGHT as usize),
+            line_sprites: Vec::with_capacity(GPU::MAX_SPRITES_PER_LINE),
+            bg_colors: vec![0; GPU::SCREEN_WIDTH as usize],
+            control: 0,
+            stat_interrupts: 0,
+            scroll_y: 0,
+            scroll_x: 0,
+            y_compare: 0,
+            bg_palette: 0,
+            obj_palette_0: 0,
+            obj_palette_1: 0,
+            window_y: 0,
+            window_x: 0,
+            line_cycles: 0,
             current_line: 0,
+            window_line: 0,
+            window_y_triggered: false,
+            pixel_transfer_clocks: GPU::VRAM_ACCESS_SCANLINE_CLOCKS,
             mode: GPUMode::SearchingOAM,
-            stopped: false,
-        }
+            stat_mode: GPUMode::SearchingOAM,
+            stat_line: false,
+            coincidence: false,
+            lcd_turned_on: false,
+            stopped: true, // the LCD is off until LCDC is written
+        };
+        gpu.screen.fill_with_color();
+        gpu
+    }
+
+    pub fn screen(&self) -> &Canvas {
+        &self.screen
+    }
+
+    pub fn read_vram(&self, address: u16) -> u8 {
+        self.vram[(address - GPU::VRAM) as usize]
+    }
+
+    pub fn write_vram(&mut self, address: u16, data: u8) {
+        self.vram[(address - GPU::VRAM) as usize] = data;
     }
 
-    pub fn tick(&mut self, bus: &mut Bus, canvas: &mut Canvas, window: &mut Window) {
-        let control_register = ControlRegister::fetch(bus);
-        if control_register.display_enabled {
-            if self.stopped == false {
-                canvas.set_draw_color(Color::WHITE);
-                canvas.fill_with_color();
-                canvas
-                    .update_window(window)
-                    .expect("Couldn't update render window");
+    pub fn read_oam(&self, address: u16) -> u8 {
+        self.oam[(address - GPU::OAM) as usize]
+    }
+
+    pub fn write_oam(&mut self, address: u16, data: u8) {
+        self.oam[(address - GPU::OAM) as usize] = data;
+    }
 
-                self.current_line = 0;
-                self.clock_cycles = 0;
-                self.mode = GPUMode::HBlank;
-                self.stopped = true;
+    pub fn read_register(&self, address: u16) -> u8 {
+        match address {
+            GPU::CONTROL_REGISTER => self.control,
+            GPU::STATUS_REGISTER => {
+                // the first M-cycle of a line doesn't show LY = LYC nor the OAM scan yet,
+                // even though the interrupts already fire
+                let starting = self.current_line != 0 && self.line_cycles < GPU::LINE_START_CLOCKS;
+                let coincidence = if self.coincidence && !starting {
+                    0b100
+                } else {
+                    0
+                };
+                let mode = match self.mode {
+                    GPUMode::SearchingOAM if starting => GPUMode::HBlank.as_u8(),
+                    _ => self.stat_mode.as_u8(),
+                };
+                0b10000000 | self.stat_interrupts | coincidence | mode
             }
-            return;
+            GPU::SCROLL_Y => self.scroll_y,
+            GPU::SCROLL_X => self.scroll_x,
+            GPU::Y_COORDINATE => self.ly(),
+            GPU::Y_COMPARE => self.y_compare,
+            GPU::BG_PALETTE => self.bg_palette,
+            GPU::OBJ_PALETTE_0 => self.obj_palette_0,
+            GPU::OBJ_PALETTE_1 => self.obj_palette_1,
+            GPU::WINDOW_Y => self.window_y,
+            GPU::WINDOW_X => self.window_x,
+            _ => panic!("Address {:#06x} is not a LCD register", address),
         }
+    }
 
-        // check if DMA transfer was started
-        let dma = bus.fetch_byte(GPU::DMA_TRANSFER_REGISTER) as u16;
-        if dma != 0 {
-            bus.set_byte(GPU::DMA_TRANSFER_REGISTER, 0);
-            for i in 0..=0x9F {
-                let content = bus.fetch_byte(((dma << 8) + i) as u16);
-                bus.set_byte(0xFE00 + i, content);
+    // Returns true when the write requests the STAT interrupt
+    pub fn write_register(&mut self, address: u16, data: u8) -> bool {
+        match address {
+            GPU::CONTROL_REGISTER => {
+                let was_enabled = ControlRegister::from_byte(self.control).display_enabled;
+                self.control = data;
+                match (
+                    was_enabled,
+                    ControlRegister::from_byte(data).display_enabled,
+                ) {
+                    (true, false) => self.turn_off(),
+                    (false, true) => self.turn_on(),
+                    _ => {}
+                }
             }
+            GPU::STATUS_REGISTER => self.stat_interrupts = data & 0b1111000,
+            GPU::SCROLL_Y => self.scroll_y = data,
+            GPU::SCROLL_X => self.scroll_x = data,
+            GPU::Y_COORDINATE => {} // read only
+            GPU::Y_COMPARE => self.y_compare = data,
+            GPU::BG_PALETTE => self.bg_palette = data,
+            GPU::OBJ_PALETTE_0 => self.obj_palette_0 = data,
+            GPU::OBJ_PALETTE_1 => self.obj_palette_1 = data,
+            GPU::WINDOW_Y => self.window_y = data,
+            GPU::WINDOW_X => self.window_x = data,
+            _ => panic!("Address {:#06x} is not a LCD register", address),
+        }
+        // the STAT line reacts right away to the LCD, STAT and LYC writes
+        self.update_stat_line()
+    }
+
+    fn turn_off(&mut self) {
+        self.screen.set_draw_color(Color::WHITE);
+        self.screen.fill_with_color();
+
+        self.current_line = 0;
+        self.line_cycles = 0;
+        self.mode = GPUMode::HBlank;
+        self.stat_mode = GPUMode::HBlank;
+        self.stopped = true;
+    }
+
+    // Turning the LCD back on starts a new frame, its first line has
+    // no OAM scan and reads as HBlank until the pixel transfer
+    fn turn_on(&mut self) {
+        self.stopped = false;
+        self.start_frame();
+        self.start_line();
+        self.mode = GPUMode::HBlank;
+        self.stat_mode = GPUMode::HBlank;
+        self.lcd_turned_on = true;
+    }
+
+    // LY, except on the last line where it already reads 0 for most of the line
+    fn ly(&self) -> u8 {
+        if self.current_line == GPU::LINE_VBLANK_END && self.line_cycles >= GPU::LINE_153_LY_CLOCKS
+        {
+            0
+        } else {
+            self.current_line
         }
+    }
+
+    // Line LYC is compared with. On the last line it lags behind LY: 153 still
+    // matches for a M-cycle after LY reads 0, then nothing does until the
+    // comparison with 0 starts a M-cycle later
+    fn ly_for_compare(&self) -> Option<u8> {
+        if self.current_line != GPU::LINE_VBLANK_END {
+            return Some(self.current_line);
+        }
+        let line_153_end = GPU::LINE_153_LY_CLOCKS + GPU::LY_COMPARE_CLOCKS;
+        match self.line_cycles {
+            cycles if cycles < line_153_end => Some(GPU::LINE_VBLANK_END),
+            cycles if cycles < line_153_end + GPU::LY_COMPARE_CLOCKS => None,
+            _ => Some(0),
+        }
+    }
 
-        self.clock_cycles += 1;
+    fn is_stat_line_high(&self) -> bool {
+        let source = match self.mode {
+            GPUMode::HBlank => GPU::HBLANK_INTERRUPT,
+            // the OAM source also fires when entering VBlank
+            GPUMode::VBlank
+                if self.current_line == GPU::SCREEN_HEIGHT
+                    && self.line_cycles == GPU::LINE_START_CLOCKS =>
+            {
+                GPU::VBLANK_INTERRUPT | GPU::OAM_INTERRUPT
+            }
+            GPUMode::VBlank => GPU::VBLANK_INTERRUPT,
+            GPUMode::SearchingOAM => GPU::OAM_INTERRUPT,
+            GPUMode::SearchingVRAM => 0,
+        };
+        let coincidence = if self.coincidence {
+            GPU::COINCIDENCE_INTERRUPT
+        } else {
+            0
+        };
+        self.stat_interrupts & (source | coincidence) != 0
+    }
+
+    // Returns true on the rising edge of the STAT line: while a source keeps
+    // it high, another one going active doesn't request a new interrupt
+    fn update_stat_line(&mut self) -> bool {
+        if self.stopped {
+            // the STAT line and the LY = LYC flag keep their level while the LCD is off
+            return false;
+        }
+        self.coincidence = self.ly_for_compare() == Some(self.y_compare);
+        let stat_line = self.is_stat_line_high();
+        let stat = stat_line && !self.stat_line;
+        self.stat_line = stat_line;
+        stat
+    }
+
+    // Advance by one clock cycle. STAT shows the mode the PPU was in a clock
+    // ago, the interrupts fire right away
+    pub fn tick(&mut self) -> GPUInterrupts {
+        self.stat_mode = self.mode;
+        let vblank = self.step_mode();
+        let stat = self.update_stat_line();
+
+        GPUInterrupts { vblank, stat }
+    }
+
+    // Returns true when entering VBlank
+    fn step_mode(&mut self) -> bool {
+        if self.stopped {
+            return false;
+        }
+
+        let mut vblank = false;
+        self.line_cycles += 1;
         match self.mode {
+            GPUMode::SearchingOAM => {
+                if self.line_cycles == GPU::OAM_ACCESS_SCANLINE_CLOCKS {
+                    self.start_pixel_transfer();
+                }
+            }
+            GPUMode::SearchingVRAM => {
+                if self.line_cycles == GPU::OAM_ACCESS_SCANLINE_CLOCKS + self.pixel_transfer_clocks
+                {
+                    self.mode = GPUMode::HBlank;
+                    self.write_scanline();
+                }
+            }
             GPUMode::HBlank => {
-                if self.clock_cycles == GPU::HORIZONTAL_BLANK_CLOCKS {
-                    // hblank ends
-                    self.clock_cycles = 0;
+                if self.lcd_turned_on && self.line_cycles == GPU::OAM_ACCESS_SCANLINE_CLOCKS {
+                    self.lcd_turned_on = false;
+                    self.start_pixel_transfer();
+                } else if self.current_line == GPU::SCREEN_HEIGHT
+                    && self.line_cycles == GPU::LINE_START_CLOCKS
+                {
+                    self.mode = GPUMode::VBlank;
+                    vblank = true;
+                } else if self.line_cycles == GPU::LINE_CLOCKS {
+                    self.line_cycles = 0;
                     self.current_line += 1;
-                    if self.current_line == GPU::MAX_LINE {
-                        // beginning hblank of last line => vblank
-                        self.mode = GPUMode::VBlank;
-                        let requested = bus.fetch_byte(0xFF0F);
-                        bus.set_byte(0xFF0F, requested | 1);
-                        // eventually render canvas here
-                        self.render_canvas(canvas, window);
-                    } else {
-                        self.mode = GPUMode::SearchingOAM; // hblank over, start scanning again
+                    // VBlank only starts a M-cycle into its first line
+                    if self.current_line != GPU::SCREEN_HEIGHT {
+                        self.start_line();
                     }
                 }
             }
             GPUMode::VBlank => {
-                if self.clock_cycles == GPU::VERTICAL_BLANCK_LINE_CLOCKS {
-                    // current vblank line ends
-                    self.clock_cycles = 0;
+                // and goes on for a M-cycle into the first line of the next frame
+                if self.current_line == 0 && self.line_cycles == GPU::LINE_START_CLOCKS {
+                    self.start_line();
+                } else if self.line_cycles == GPU::LINE_CLOCKS {
+                    self.line_cycles = 0;
                     self.current_line += 1;
                     if self.current_line > GPU::LINE_VBLANK_END {
-                        // ending vblank, resume scanning
-                        self.mode = GPUMode::SearchingOAM;
-                        self.current_line = 0;
-                        // lock oam
+                        self.start_frame();
                     }
                 }
             }
-            GPUMode::SearchingOAM => {
-                if self.clock_cycles == GPU::OAM_ACCESS_SCANLINE_CLOCKS {
-                    // first part of scanning
-                    self.clock_cycles = 0;
-                    // unlock oam
-                    // lock vram
-                    self.mode = GPUMode::SearchingVRAM;
-                }
-            }
-            GPUMode::SearchingVRAM => {
-                if self.clock_cycles == GPU::VRAM_ACCESS_SCANLINE_CLOCKS {
-                    // horizontal scanning ends
-                    self.clock_cycles = 0;
-                    self.mode = GPUMode::HBlank;
-                    // write scanline to canvas
-                    self.write_scanline(bus, canvas);
-                }
-            }
         }
 
-        // update io ports
-        bus.set_byte(GPU::Y_COORDINATE, self.current_line);
-        bus.set_byte(GPU::STATUS_REGISTER, self.mode.as_u8());
+        vblank
     }
 
-    fn choose_color_from_palette(&self, bus: &Bus, canvas: &mut Canvas, color_nb: u8) {
-        let palette = bus.fetch_byte(GPU::BG_PALETTE);
-        let shade = match color_nb {
-            3 => (palette & 0b11000000) >> 6,
-            2 => (palette & 0b110000) >> 4,
-            1 => (palette & 0b1100) >> 2,
-            0 => palette & 0b11,
-            _ => panic!("Palette color_nb {} not valid !", color_nb),
+    fn start_frame(&mut self) {
+        self.current_line = 0;
+        self.line_cycles = 0;
+        self.window_line = 0;
+        self.window_y_triggered = false;
+    }
+
+    fn start_pixel_transfer(&mut self) {
+        self.mode = GPUMode::SearchingVRAM;
+        self.scan_oam();
+        self.pixel_transfer_clocks = self.pixel_transfer_length();
+    }
+
+    fn start_line(&mut self) {
+        self.mode = GPUMode::SearchingOAM;
+        if self.current_line == self.window_y {
+            self.window_y_triggered = true;
+        }
+    }
+
+    fn is_window_visible(&self) -> bool {
+        let control_register = ControlRegister::from_byte(self.control);
+        control_register.window_enabled
+            && control_register.bg_window_enable_priority
+            && self.window_y_triggered
+            && self.window_x < GPU::SCREEN_WIDTH + GPU::WINDOW_X_OFFSET
+    }
+
+    fn sprite_height(&self) -> i32 {
+        match ControlRegister::from_byte(self.control).obj_size {
+            OBJSize::Size8x8 => 8,
+            OBJSize::Sixe8x16 => 16,
+        }
+    }
+
+    // Select the first 10 sprites of OAM overlapping the current line, whatever
+    // their X. On DMG the one with the smallest X is drawn on top, then the first in OAM
+    fn scan_oam(&mut self) {
+        let height = self.sprite_height();
+        let line = self.current_line as i32;
+        let mut sprites: Vec<Sprite> = self
+            .oam
+            .chunks(4)
+            .map(|entry| Sprite {
+                y: entry[0] as i32 - 16,
+                x: entry[1] as i32 - 8,
+                tile: entry[2],
+                flags: entry[3],
+            })
+            .filter(|sprite| (sprite.y..sprite.y + height).contains(&line))
+            .take(GPU::MAX_SPRITES_PER_LINE)
+            .collect();
+        // the sort is stable, OAM order is kept between sprites at the same X
+        sprites.sort_by_key(|sprite| sprite.x);
+        self.line_sprites = sprites;
+    }
+
+    // Screen X of the first window pixel on the current line, negative when
+    // the window starts left of the screen
+    fn window_start(&self) -> Option<i32> {
+        if self.is_window_visible() {
+            Some(self.window_x as i32 - GPU::WINDOW_X_OFFSET as i32)
+        } else {
+            None
+        }
+    }
+
+    // Mode 3 takes longer when the fetcher has to discard the pixels left of
+    // the screen, restart for the window or fetch sprites
+    fn pixel_transfer_length(&self) -> u16 {
+        let window_start = self.window_start();
+        let discarded = match window_start {
+            // the window covers the whole line, its pixels left of the screen
+            // are thrown away instead of the scrolled out background ones
+            Some(start) if start < 0 => -start as u16,
+            _ => (self.scroll_x % 8) as u16,
         };
+        let mut clocks = GPU::VRAM_ACCESS_SCANLINE_CLOCKS + discarded;
+        if window_start.is_some() {
+            clocks += GPU::WINDOW_FETCH_CLOCKS;
+        }
+        if ControlRegister::from_byte(self.control).obj_enabled {
+            clocks += self.sprite_fetch_clocks(window_start);
+        }
+        clocks
+    }
+
+    // Each sprite stalls the fetcher for 6 clocks. The first sprite over a
+    // background or window tile also waits for the fetch of that tile to be
+    // done, up to 5 more clocks the further left it is. Sprites past the
+    // right edge are never reached
+    fn sprite_fetch_clocks(&self, window_start: Option<i32>) -> u16 {
+        let mut clocks = 0;
+        let mut last_tile = None;
+        for sprite in &self.line_sprites {
+            if sprite.x >= GPU::SCREEN_WIDTH as i32 {
+                continue;
+            }
+            // the fetcher works on tiles aligned with the scrolled background,
+            // then with WX once the window started
+            let (in_window, position) = match window_start {
+                Some(start) if sprite.x >= start => (true, (sprite.x - start + 8) as u16),
+                _ => (false, (sprite.x + 8 + (self.scroll_x % 8) as i32) as u16),
+            };
+            if last_tile != Some((in_window, position / 8)) {
+                last_tile = Some((in_window, position / 8));
+                clocks += 5u16.saturating_sub(position % 8);
+            }
+            clocks += 6;
+        }
+        clocks
+    }
+
+    fn choose_color_from_palette(&mut self, palette: u8, color_nb: u8) {
+        let shade = (palette >> (2 * color_nb)) & 0b11;
 
         match shade {
-            3 => canvas.set_draw_color(Color::BLACK),
-            2 => canvas.set_draw_color(Color::DARK_GRAY),
-            1 => canvas.set_draw_color(Color::LIGHT_GRAY),
-            0 => canvas.set_draw_color(Color::WHITE),
+            3 => self.screen.set_draw_color(Color::BLACK),
+            2 => self.screen.set_draw_color(Color::DARK_GRAY),
+            1 => self.screen.set_draw_color(Color::LIGHT_GRAY),
+            0 => self.screen.set_draw_color(Color::WHITE),
             _ => panic!("Shade {} not valid!", shade),
         }
     }
 
-    fn render_background_line(&self, bus: &Bus, canvas: &mut Canvas) {
-        let tileset = ControlRegister::fetch(bus).bg_window_tile_data_area;
-        let tileset_address = tileset.address();
-        //let tileset = bus.fetch_byte(GPU::CONTROL_REGISTER) & 0b10000;
-        let scroll_x = bus.fetch_byte(GPU::SCROLL_X);
-        let scroll_y = bus.fetch_byte(GPU::SCROLL_Y);
-
-        let nb_line = (self.current_line.wrapping_add(scroll_y) / 8) % 32; // ith line of sprites
-
-        for i in 0..GPU::SCREEN_WIDTH {
-            let index = ((i + scroll_x) / 8) as u16;
-            let nb_sprite = bus.fetch_byte(GPU::BG_MAP_1 + ((nb_line as u16) * 32) + index);
-            let pos_x_in_sprite = ((i as u8) + scroll_x) % 8;
-            let pos_y_in_sprite = self.current_line.wrapping_add(scroll_y) % 8;
-
-            // drawing the sprite
-            let shift = 0b10000000 >> pos_x_in_sprite;
-            let shade: u8 = match tileset {
-                BGWindowTileDataArea::Area9000 => {
-                    let relative_address_offset = 16 * (nb_sprite as i16);
-                    let raw_1 = bus.fetch_byte(
-                        ((tileset_address as i16)
-                            + relative_address_offset
-                            + ((pos_y_in_sprite as i16) * 2)) as u16,
-                    );
-                    let raw_1 = (raw_1 & shift) >> (7 - pos_x_in_sprite);
-                    let raw_2 = bus.fetch_byte(
-                        ((tileset_address as i16)
-                            + relative_address_offset
-                            + ((pos_y_in_sprite as i16) * 2 + 1)) as u16,
-                    );
-                    let raw_2 = (raw_2 & shift) >> (7 - pos_x_in_sprite);
-                    raw_1 + (raw_2 << 1)
-                }
-                BGWindowTileDataArea::Area8000 => {
-                    let address_offset = 16 * (nb_sprite as u16);
-                    let raw_1 = bus.fetch_byte(
-                        tileset_address + address_offset + ((pos_y_in_sprite as u16) * 2),
-                    );
-                    let raw_1 = (raw_1 & shift) >> (7 - pos_x_in_sprite);
-                    let raw_2 = bus.fetch_byte(
-                        tileset_address + address_offset + ((pos_y_in_sprite as u16) * 2 + 1),
-                    );
-                    let raw_2 = (raw_2 & shift) >> (7 - pos_x_in_sprite);
-                    raw_1 + (raw_2 << 1)
-                }
+    // Color number of a pixel of the 32x32 tiles map at the given address
+    fn tile_map_pixel(&self, map_address: u16, x: u8, y: u8) -> u8 {
+        let tileset = ControlRegister::from_byte(self.control).bg_window_tile_data_area;
+        let index = (y as u16 / 8) * 32 + (x as u16 / 8);
+        let tile = self.read_vram(map_address + index);
+        let row_address = tileset.row_address(tile, y % 8);
+        let low = self.read_vram(row_address);
+        let high = self.read_vram(row_address + 1);
+        let shift = 7 - (x % 8);
+        ((low >> shift) & 1) | (((high >> shift) & 1) << 1)
+    }
+
+    // Background and window share the same pixels, the window covers
+    // everything right of WX - 7 once LY reached WY
+    fn render_background_line(&mut self) {
+        let control_register = ControlRegister::from_byte(self.control);
+        let bg_map = control_register.bg_tile_map_area.address();
+        let window_map = control_register.window_tile_map_area.address();
+        let window_visible = self.is_window_visible();
+        let line = self.current_line;
+
+        for x in 0..GPU::SCREEN_WIDTH {
+            let color_nb = if window_visible && x + GPU::WINDOW_X_OFFSET >= self.window_x {
+                let window_x = x + GPU::WINDOW_X_OFFSET - self.window_x;
+                self.tile_map_pixel(window_map, window_x, self.window_line)
+            } else {
+                self.tile_map_pixel(
+                    bg_map,
+                    x.wrapping_add(self.scroll_x),
+                    line.wrapping_add(self.scroll_y),
+                )
             };
 
-            self.choose_color_from_palette(bus, canvas, shade);
-            canvas
-                .set_pixel(i as usize, self.current_line as usize)
+            self.bg_colors[x as usize] = color_nb;
+            self.choose_color_from_palette(self.bg_palette, color_nb);
+            self.screen
+                .set_pixel(x as usize, line as usize)
                 .expect("Couldn't set pixel in canvas");
         }
+
+        if window_visible {
+            self.window_line += 1;
+        }
     }
 
-    fn check_8x8_sprite_rendering(&self, x: i32, y: i32) -> bool {
-        ((self.current_line as u16 as i32) >= y && (self.current_line as u16 as i32) <= y + 7)
-            && (x > 0 && x < 168)
+    // Color number of a sprite pixel on the current line, 0 is transparent
+    fn sprite_pixel(&self, sprite: &Sprite, x: i32) -> u8 {
+        let height = self.sprite_height();
+        let mut row = self.current_line as i32 - sprite.y;
+        if sprite.flags & Sprite::Y_FLIP != 0 {
+            row = height - 1 - row;
+        }
+        let mut column = x - sprite.x;
+        if sprite.flags & Sprite::X_FLIP != 0 {
+            column = 7 - column;
+        }
+        // 8x16 sprites use a pair of tiles, the low bit of the tile number is ignored
+        let tile = if height == 16 {
+            sprite.tile & 0xFE
+        } else {
+            sprite.tile
+        };
+
+        let row_address = GPU::TILESET_1 + 16 * tile as u16 + 2 * row as u16;
+        let low = self.read_vram(row_address);
+        let high = self.read_vram(row_address + 1);
+        let shift = 7 - column;
+        ((low >> shift) & 1) | (((high >> shift) & 1) << 1)
     }
 
-    fn render_sprite_line(&self, bus: &Bus, canvas: &mut Canvas) {
-        let sprite_size = ControlRegister::fetch(bus).obj_size;
-        for i in 0..=40 {
-            let base_address = GPU::OAM + i * 4;
-            let y_pos = (bus.fetch_byte(base_address) as u16 as i32) - 16;
-            let x_pos = (bus.fetch_byte(base_address + 1) as u16 as i32) - 8;
-            let tile_nb = bus.fetch_byte(base_address + 2);
-            let flags = bus.fetch_byte(base_address + 3);
+    fn render_sprite_line(&mut self) {
+        for x in 0..GPU::SCREEN_WIDTH as i32 {
+            // the first opaque pixel wins, even when it ends up behind the background
+            let pixel = self
+                .line_sprites
+                .iter()
+                .filter(|sprite| (sprite.x..sprite.x + 8).contains(&x))
+                .map(|sprite| (sprite, self.sprite_pixel(sprite, x)))
+                .find(|(_, color_nb)| *color_nb != 0);
+            let (sprite, color_nb) = match pixel {
+                Some(pixel) => pixel,
+                None => continue,
+            };
+            if sprite.flags & Sprite::BEHIND_BG != 0 && self.bg_colors[x as usize] != 0 {
+                continue;
+            }
 
-            match sprite_size {
-                OBJSize::Size8x8 => {
-                    if self.check_8x8_sprite_rendering(x_pos, y_pos) == false {
-                        continue;
-                    }
-                }
-                OBJSize::Sixe8x16 => panic!("8x16 sprites are not implemented yet!"),
+            let palette = if sprite.flags & Sprite::PALETTE_1 != 0 {
+                self.obj_palette_1
+            } else {
+                self.obj_palette_0
+            };
+            self.choose_color_from_palette(palette, color_nb);
+            self.screen
+                .set_pixel(x as usize, self.current_line as usize)
+                .expect("Couldn't set pixel in canvas");
+        }
+    }
+
+    fn write_scanline(&mut self) {
+        let control_register = ControlRegister::from_byte(self.control);
+        if control_register.bg_window_enable_priority {
+            self.render_background_line();
+        } else {
+            // background and window are blank
+            self.screen.set_draw_color(Color::WHITE);
+            self.bg_colors.fill(0);
+            for x in 0..GPU::SCREEN_WIDTH {
+                self.screen
+                    .set_pixel(x as usize, self.current_line as usize)
+                    .expect("Couldn't set pixel in canvas");
             }
-            // sprite is valid to render
-            let mut sprite: Vec<u8> = (0..16)
-                .map(|k| bus.fetch_byte(GPU::TILESET_1 + 16 * (tile_nb as u16) + k))
-                .collect();
-            if flags & 0b1000000 == 1 {
-                // Y flip
-                sprite.reverse();
+        }
+        if control_register.obj_enabled {
+            self.render_sprite_line();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LCD_ON: u8 = 0x91;
+    const OBJ_ENABLED: u8 = 0b10;
+    const WINDOW_ENABLED: u8 = 0b100000;
+    const WINDOW_MAP_9C00: u8 = 0b1000000;
+    const TILE_DATA_8000: u8 = 0b10000;
+    const OBJ_8X16: u8 = 0b100;
+
+    fn turned_on(control: u8) -> GPU {
+        let mut gpu = GPU::new();
+        gpu.write_register(GPU::CONTROL_REGISTER, control);
+        gpu
+    }
+
+    fn mode(gpu: &GPU) -> u8 {
+        gpu.read_register(GPU::STATUS_REGISTER) & 0b11
+    }
+
+    // Clocks until the mode read from STAT changes, and the new mode
+    fn run_mode(gpu: &mut GPU) -> (u16, u8) {
+        let start = mode(gpu);
+        let mut clocks = 0;
+        while mode(gpu) == start {
+            gpu.tick();
+            clocks += 1;
+        }
+        (clocks, mode(gpu))
+    }
+
+    // Palettes mapping every color number to the shade of the same number,
+    // except OBP1 which reverses them
+    fn with_palettes() -> GPU {
+        let mut gpu = GPU::new();
+        gpu.write_register(GPU::BG_PALETTE, 0xE4);
+        gpu.write_register(GPU::OBJ_PALETTE_0, 0xE4);
+        gpu.write_register(GPU::OBJ_PALETTE_1, 0x1B);
+        gpu
+    }
+
+    // Set a row of a tile, the pixels of the mask get the color number and the others 0
+    fn set_tile_row(gpu: &mut GPU, address: u16, row: u16, color_nb: u8, mask: u8) {
+        let plane = |bit: u8| if color_nb & bit != 0 { mask } else { 0 };
+        gpu.write_vram(address + 2 * row, plane(1));
+        gpu.write_vram(address + 2 * row + 1, plane(2));
+    }
+
+    fn fill_tile(gpu: &mut GPU, address: u16, color_nb: u8) {
+        for row in 0..8 {
+            set_tile_row(gpu, address, row, color_nb, 0xFF);
+        }
+    }
+
+    fn fill_map(gpu: &mut GPU, address: u16, tile: u8) {
+        for index in 0..0x400 {
+            gpu.write_vram(address + index, tile);
+        }
+    }
+
+    // OAM entry, in OAM coordinates: the sprite at Y 16 and X 8 is in the top left corner
+    fn place_sprite(gpu: &mut GPU, index: u16, y: u8, x: u8, tile: u8, flags: u8) {
+        for (offset, &byte) in [y, x, tile, flags].iter().enumerate() {
+            gpu.write_oam(GPU::OAM + 4 * index + offset as u16, byte);
+        }
+    }
+
+    // Turn the LCD on and run until the given number of lines are drawn
+    fn draw_lines(gpu: &mut GPU, control: u8, lines: u32) {
+        gpu.write_register(GPU::CONTROL_REGISTER, control);
+        count_interrupts(gpu, lines * 456);
+    }
+
+    // Shades of a line of the screen, from 0 for white to 3 for black
+    fn shades(gpu: &GPU, line: usize) -> Vec<u8> {
+        let colors = [
+            Color::WHITE,
+            Color::LIGHT_GRAY,
+            Color::DARK_GRAY,
+            Color::BLACK,
+        ];
+        (0..GPU::SCREEN_WIDTH as usize)
+            .map(|x| {
+                let pixel = gpu.screen.get_pixel(x, line).unwrap();
+                colors
+                    .iter()
+                    .position(|color| color.as_u32() == pixel)
+                    .unwrap() as u8
+            })
+            .collect()
+    }
+
+    // Number of (VBlank, STAT) interrupts requested during the given clocks
+    fn count_interrupts(gpu: &mut GPU, clocks: u32) -> (u32, u32) {
+        (0..clocks).fold((0, 0), |(vblank, stat), _| {
+            let interrupts = gpu.tick();
+            (
+                vblank + interrupts.vblank as u32,
+                stat + interrupts.stat as u32,
+            )
+        })
+    }
+
+    #[test]
+    fn skips_oam_scan_on_first_line() {
+        let mut gpu = turned_on(LCD_ON);
+        // STAT shows the pixel transfer and HBlank a clock late
+        assert_eq!(run_mode(&mut gpu), (80 + 1, 3));
+        assert_eq!(run_mode(&mut gpu), (172, 0));
+        assert_eq!(run_mode(&mut gpu), (204 + 4 - 1, 2));
+        assert_eq!(gpu.read_register(GPU::Y_COORDINATE), 1);
+    }
+
+    #[test]
+    fn goes_through_modes_of_a_line() {
+        let mut gpu = turned_on(LCD_ON);
+        count_interrupts(&mut gpu, 456);
+        assert_eq!(gpu.read_register(GPU::Y_COORDINATE), 1);
+        // the OAM scan only shows up a M-cycle into the line
+        assert_eq!(run_mode(&mut gpu), (4, 2));
+        assert_eq!(run_mode(&mut gpu), (76 + 1, 3));
+        assert_eq!(run_mode(&mut gpu), (172, 0));
+        assert_eq!(run_mode(&mut gpu), (204 + 4 - 1, 2));
+    }
+
+    #[test]
+    fn requests_vblank_once_per_frame() {
+        let mut gpu = turned_on(LCD_ON);
+        assert_eq!(count_interrupts(&mut gpu, 144 * 456 + 3), (0, 0));
+        assert_eq!(count_interrupts(&mut gpu, 1), (1, 0));
+        assert_eq!(count_interrupts(&mut gpu, 1), (0, 0));
+        assert_eq!(mode(&gpu), 1);
+        assert_eq!(count_interrupts(&mut gpu, 70224 - 2), (0, 0));
+        assert_eq!(count_interrupts(&mut gpu, 1), (1, 0));
+    }
+
+    #[test]
+    fn requests_stat_on_rising_edge_only() {
+        // HBlank, OAM and both: the line stays high from HBlank into the
+        // OAM scan, so combining them doesn't add interrupts
+        for &sources in &[0b1000, 0b100000, 0b101000] {
+            let mut gpu = turned_on(LCD_ON);
+            count_interrupts(&mut gpu, 456);
+            gpu.write_register(GPU::STATUS_REGISTER, sources);
+            assert_eq!(count_interrupts(&mut gpu, 10 * 456).1, 10);
+        }
+    }
+
+    #[test]
+    fn compares_ly_with_lyc() {
+        let mut gpu = turned_on(LCD_ON);
+        gpu.write_register(GPU::Y_COMPARE, 2);
+        gpu.write_register(GPU::STATUS_REGISTER, GPU::COINCIDENCE_INTERRUPT);
+        assert_eq!(count_interrupts(&mut gpu, 2 * 456 - 1).1, 0);
+        // the interrupt fires at the start of the line, the flag shows a M-cycle later
+        assert_eq!(count_interrupts(&mut gpu, 1).1, 1);
+        assert_eq!(gpu.read_register(GPU::STATUS_REGISTER) & 0b100, 0);
+        count_interrupts(&mut gpu, 4);
+        assert_eq!(gpu.read_register(GPU::STATUS_REGISTER) & 0b100, 0b100);
+        assert_eq!(count_interrupts(&mut gpu, 456).1, 0);
+        assert_eq!(gpu.read_register(GPU::STATUS_REGISTER) & 0b100, 0);
+    }
+
+    #[test]
+    fn reads_ly_0_during_most_of_last_line() {
+        let mut gpu = turned_on(LCD_ON);
+        gpu.write_register(GPU::Y_COMPARE, 0);
+        count_interrupts(&mut gpu, 153 * 456);
+        assert_eq!(gpu.ly_for_compare(), Some(153));
+        count_interrupts(&mut gpu, 4);
+        assert_eq!(gpu.read_register(GPU::Y_COORDINATE), 0);
+        assert_eq!(gpu.ly_for_compare(), Some(153));
+        count_interrupts(&mut gpu, 4);
+        assert_eq!(gpu.ly_for_compare(), None);
+        count_interrupts(&mut gpu, 4);
+        assert_eq!(gpu.ly_for_compare(), Some(0));
+        assert_eq!(gpu.read_register(GPU::STATUS_REGISTER) & 0b100, 0b100);
+    }
+
+    #[test]
+    fn lengthens_pixel_transfer_for_sprites() {
+        // scroll X, OAM X of the sprites on the first line, mode 3 length
+        let cases: &[(u8, &[u8], u16)] = &[
+            (0, &[], 172),
+            (3, &[], 175),
+            (0, &[8], 183),
+            (0, &[13], 178),
+            (0, &[8, 10], 189),
+            (0, &[8, 16], 194),
+            (3, &[10], 181),
+            (0, &[0], 183),
+            (0, &[168], 172),
+            (0, &[8; 10], 237),
+            (0, &[8; 12], 237),
+        ];
+        for &(scroll_x, xs, length) in cases {
+            let mut gpu = GPU::new();
+            for (index, &x) in xs.iter().enumerate() {
+                gpu.write_oam(GPU::OAM + 4 * index as u16, 16);
+                gpu.write_oam(GPU::OAM + 4 * index as u16 + 1, x);
             }
-            // get Y line to render
-            let row_index = ((self.current_line as u16 as i32) - y_pos) as usize;
-            let row_1 = sprite[2 * row_index];
-            let row_2 = sprite[2 * row_index + 1];
-
-            let x_flip = flags & 0b100000;
-
-            for i in 0..8 {
-                let (shade_1, shade_2) = if x_flip == 1 {
-                    (
-                        (row_1 & (0b10000000 >> (7 - i))) >> (i),
-                        (row_2 & (0b10000000 >> (7 - i))) >> (i),
-                    )
-                } else {
-                    (
-                        (row_1 & (0b10000000 >> i)) >> (7 - i),
-                        (row_2 & (0b10000000 >> i)) >> (7 - i),
-                    )
-                };
+            gpu.write_register(GPU::SCROLL_X, scroll_x);
+            gpu.write_register(GPU::CONTROL_REGISTER, LCD_ON | OBJ_ENABLED);
+            run_mode(&mut gpu);
+            assert_eq!(run_mode(&mut gpu).0, length, "SCX {} X {:?}", scroll_x, xs);
+        }
+    }
 
-                self.choose_color_from_palette(bus, canvas, shade_1 + (shade_2 << 1));
-                canvas
-                    .set_pixel((x_pos + i) as usize, self.current_line as usize)
-                    .expect("Couldn't set pixel in canvas");
+    #[test]
+    fn lengthens_pixel_transfer_for_the_window() {
+        // WX, scroll X, OAM X of the sprites on the first line, mode 3 length
+        let cases: &[(u8, u8, &[u8], u16)] = &[
+            (7, 0, &[], 178),
+            (80, 3, &[], 181),
+            (166, 0, &[], 178),
+            (167, 0, &[], 172),
+            (3, 0, &[], 182),
+            (0, 3, &[], 185),
+            (87, 0, &[88], 189),
+            (84, 0, &[88], 186),
+            (84, 0, &[80], 189),
+        ];
+        for &(window_x, scroll_x, xs, length) in cases {
+            let mut gpu = GPU::new();
+            for (index, &x) in xs.iter().enumerate() {
+                gpu.write_oam(GPU::OAM + 4 * index as u16, 16);
+                gpu.write_oam(GPU::OAM + 4 * index as u16 + 1, x);
             }
+            gpu.write_register(GPU::WINDOW_X, window_x);
+            gpu.write_register(GPU::SCROLL_X, scroll_x);
+            gpu.write_register(GPU::CONTROL_REGISTER, LCD_ON | OBJ_ENABLED | WINDOW_ENABLED);
+            run_mode(&mut gpu);
+            assert_eq!(
+                run_mode(&mut gpu).0,
+                length,
+                "WX {} SCX {} X {:?}",
+                window_x,
+                scroll_x,
+                xs
+            );
         }
     }
 
-    fn write_scanline(&self, bus: &Bus, canvas: &mut Canvas) {
-        let control_register = ControlRegister::fetch(bus);
-        if control_register.bg_window_enable_priority {
-            self.render_background_line(bus, canvas);
+    #[test]
+    fn places_the_window_from_wx_and_wy() {
+        // WX, line, first window pixel on that line
+        let cases: &[(u8, usize, Option<usize>)] = &[
+            (50, 1, None),
+            (50, 2, Some(43)),
+            (50, 3, Some(43)),
+            (7, 2, Some(0)),
+            (166, 2, Some(159)),
+            (167, 2, None),
+        ];
+        for &(window_x, line, start) in cases {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8010, 3);
+            fill_map(&mut gpu, 0x9C00, 1);
+            gpu.write_register(GPU::WINDOW_Y, 2);
+            gpu.write_register(GPU::WINDOW_X, window_x);
+            draw_lines(&mut gpu, LCD_ON | WINDOW_ENABLED | WINDOW_MAP_9C00, 4);
+            let expected: Vec<u8> = (0..160)
+                .map(|x| {
+                    if start.is_some_and(|start| x >= start) {
+                        3
+                    } else {
+                        0
+                    }
+                })
+                .collect();
+            assert_eq!(
+                shades(&gpu, line),
+                expected,
+                "WX {} line {}",
+                window_x,
+                line
+            );
         }
-        if control_register.obj_enabled {
-            self.render_sprite_line(bus, canvas);
+    }
+
+    #[test]
+    fn selects_the_window_tile_map_with_lcdc_6() {
+        for &(map_bit, shade) in &[(0, 0), (WINDOW_MAP_9C00, 3)] {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8010, 3);
+            fill_map(&mut gpu, 0x9C00, 1);
+            gpu.write_register(GPU::WINDOW_X, 7);
+            draw_lines(&mut gpu, LCD_ON | WINDOW_ENABLED | map_bit, 1);
+            assert_eq!(shades(&gpu, 0), vec![shade; 160], "LCDC.6 {}", map_bit);
         }
     }
 
-    fn render_canvas(&self, canvas: &mut Canvas, window: &mut Window) {
-        canvas
-            .update_window(window)
-            .expect("Couldn't update window");
+    #[test]
+    fn selects_the_background_tile_data_with_lcdc_4() {
+        // tiles 0x80-0xFF are shared, 0x00-0x7F come from 0x8000 or 0x9000
+        let cases = [
+            (TILE_DATA_8000, 0x01, 1),
+            (0, 0x01, 2),
+            (TILE_DATA_8000, 0x81, 3),
+            (0, 0x81, 3),
+        ];
+        for &(data_bit, tile, shade) in &cases {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8010, 1);
+            fill_tile(&mut gpu, 0x9010, 2);
+            fill_tile(&mut gpu, 0x8810, 3);
+            fill_map(&mut gpu, 0x9800, tile);
+            draw_lines(&mut gpu, (LCD_ON & !TILE_DATA_8000) | data_bit, 1);
+            assert_eq!(
+                shades(&gpu, 0),
+                vec![shade; 160],
+                "LCDC.4 {} tile {:#04x}",
+                data_bit,
+                tile
+            );
+        }
+    }
+
+    #[test]
+    fn only_moves_the_window_line_on_lines_showing_the_window() {
+        // each row of the window tile has the color number of its index modulo 4
+        let mut gpu = with_palettes();
+        for row in 0..8 {
+            set_tile_row(&mut gpu, 0x8010, row, row as u8 % 4, 0xFF);
+        }
+        fill_map(&mut gpu, 0x9C00, 1);
+        gpu.write_register(GPU::WINDOW_X, 7);
+        draw_lines(&mut gpu, LCD_ON | WINDOW_ENABLED | WINDOW_MAP_9C00, 2);
+        // hidden during lines 2 and 3, the window picks up at its 3rd row
+        gpu.write_register(GPU::WINDOW_X, 167);
+        count_interrupts(&mut gpu, 2 * 456);
+        gpu.write_register(GPU::WINDOW_X, 7);
+        count_interrupts(&mut gpu, 2 * 456);
+        assert_eq!(shades(&gpu, 1), vec![1; 160]);
+        assert_eq!(shades(&gpu, 4), vec![2; 160]);
+        assert_eq!(shades(&gpu, 5), vec![3; 160]);
+    }
+
+    #[test]
+    fn flips_sprites() {
+        // flags, position of the top left pixel of the tile once drawn
+        let cases = [
+            (0, (0, 0)),
+            (Sprite::X_FLIP, (0, 7)),
+            (Sprite::Y_FLIP, (7, 0)),
+            (Sprite::X_FLIP | Sprite::Y_FLIP, (7, 7)),
+        ];
+        for &(flags, position) in &cases {
+            let mut gpu = with_palettes();
+            set_tile_row(&mut gpu, 0x8010, 0, 3, 0x80);
+            place_sprite(&mut gpu, 0, 16, 8, 1, flags);
+            draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 8);
+            let drawn: Vec<(usize, usize)> = (0..8)
+                .flat_map(|line| {
+                    let shades = shades(&gpu, line);
+                    (0..8)
+                        .filter(move |&x| shades[x] != 0)
+                        .map(move |x| (line, x))
+                })
+                .collect();
+            assert_eq!(drawn, vec![position], "flags {:#010b}", flags);
+        }
+    }
+
+    #[test]
+    fn picks_obp0_or_obp1() {
+        for &(flags, shade) in &[(0, 1), (Sprite::PALETTE_1, 2)] {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8010, 1);
+            place_sprite(&mut gpu, 0, 16, 8, 1, flags);
+            draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 1);
+            assert_eq!(shades(&gpu, 0)[..8], [shade; 8], "flags {:#010b}", flags);
+        }
+    }
+
+    #[test]
+    fn hides_sprites_behind_background_colors_1_to_3() {
+        // the left half of the background tiles has color 1, the right half color 0
+        for &(flags, shades_drawn) in &[(0, [3; 8]), (Sprite::BEHIND_BG, [1, 1, 1, 1, 3, 3, 3, 3])]
+        {
+            let mut gpu = with_palettes();
+            for row in 0..8 {
+                set_tile_row(&mut gpu, 0x8000, row, 1, 0xF0);
+            }
+            fill_tile(&mut gpu, 0x8010, 3);
+            place_sprite(&mut gpu, 0, 16, 8, 1, flags);
+            draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 1);
+            assert_eq!(shades(&gpu, 0)[..8], shades_drawn, "flags {:#010b}", flags);
+        }
+    }
+
+    #[test]
+    fn ignores_the_low_bit_of_the_tile_of_8x16_sprites() {
+        let mut gpu = with_palettes();
+        fill_tile(&mut gpu, 0x8020, 1);
+        fill_tile(&mut gpu, 0x8030, 2);
+        place_sprite(&mut gpu, 0, 16, 8, 3, 0);
+        draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED | OBJ_8X16, 16);
+        for line in 0..16 {
+            let shade = if line < 8 { 1 } else { 2 };
+            assert_eq!(shades(&gpu, line)[..8], [shade; 8], "line {}", line);
+        }
+    }
+
+    #[test]
+    fn shows_what_is_under_color_0_of_sprites() {
+        // the background has color 2, the sprite on top only covers its left half,
+        // the one below is drawn through the transparent pixels
+        for &(sprites, shades_drawn) in
+            &[(1, [3, 3, 3, 3, 2, 2, 2, 2]), (2, [3, 3, 3, 3, 1, 1, 1, 1])]
+        {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8000, 2);
+            for row in 0..8 {
+                set_tile_row(&mut gpu, 0x8010, row, 3, 0xF0);
+            }
+            fill_tile(&mut gpu, 0x8020, 1);
+            place_sprite(&mut gpu, 0, 16, 8, 1, 0);
+            if sprites == 2 {
+                place_sprite(&mut gpu, 1, 16, 8, 2, 0);
+            }
+            draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 1);
+            assert_eq!(shades(&gpu, 0)[..8], shades_drawn, "{} sprites", sprites);
+        }
+    }
+
+    #[test]
+    fn draws_10_sprites_per_line_at_most() {
+        let mut gpu = with_palettes();
+        fill_tile(&mut gpu, 0x8010, 3);
+        // sprites on other lines don't count, the 11th one on the line is dropped
+        place_sprite(&mut gpu, 0, 100, 8, 1, 0);
+        for index in 1..=11 {
+            place_sprite(&mut gpu, index, 16, 8 * index as u8, 1, 0);
+        }
+        draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 1);
+        let expected: Vec<u8> = (0..160).map(|x| if x < 80 { 3 } else { 0 }).collect();
+        assert_eq!(shades(&gpu, 0), expected);
+    }
+
+    #[test]
+    fn draws_lower_x_then_lower_oam_index_on_top() {
+        // OAM X of sprites 0 and 1, shades of the pixels 4 to 7
+        for &(xs, shade) in &[([12, 8], 2), ([8, 12], 1), ([8, 8], 1)] {
+            let mut gpu = with_palettes();
+            fill_tile(&mut gpu, 0x8010, 1);
+            fill_tile(&mut gpu, 0x8020, 2);
+            place_sprite(&mut gpu, 0, 16, xs[0], 1, 0);
+            place_sprite(&mut gpu, 1, 16, xs[1], 2, 0);
+            draw_lines(&mut gpu, LCD_ON | OBJ_ENABLED, 1);
+            assert_eq!(shades(&gpu, 0)[4..8], [shade; 4], "X {:?}", xs);
+        }
     }
 }
@@ -1,36 +1,173 @@
+mod bindings;
 mod bus;
 mod canvas;
 mod color;
 mod cpu;
 // mod debugger;
 mod buttons;
+mod gameboy;
 mod gpu;
+mod model;
+mod movie;
 
+use bindings::KeyBindings;
 use buttons::Buttons;
-use cpu::cpu::*;
+use gameboy::GameBoy;
 
-use std::time::Duration;
+use std::env;
+use std::fs;
+use std::path::Path;
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use canvas::Canvas;
 use minifb::{Key, Scale, ScaleMode, Window, WindowOptions};
+use model::Model;
+use movie::{MovieHeader, MoviePlayer, MovieRecorder};
+
+const CLOCK_SPEED: u64 = 4194304; // clock cycles per second
 
 fn main() {
     let x_size: usize = 160;
     let y_size: usize = 144;
     let _scale: f32 = 2.0;
 
-    let mut bus: bus::Bus = bus::Bus::new_bus(&String::from("roms/Tetris.GB"));
-    //let mut bus: bus::Bus = bus::Bus::new_bus(&String::from("roms/11-op a,(hl).gb"));
-    let mut cpu = CPU::new_cpu();
-    let mut gpu = gpu::GPU::new();
-    let mut keys = Buttons::new();
+    let mut rom_path = String::from("roms/Tetris.GB");
+    let mut model = Model::default();
+    let mut boot_rom_path = None;
+    let mut keys_path = None;
+    let mut record_path = None;
+    let mut play_path = None;
+    let mut headless = false;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--model" => {
+                let name = args.next().expect("--model expects a hardware model name");
+                model = name.parse().unwrap_or_else(|err| {
+                    eprintln!("{}", err);
+                    process::exit(1);
+                });
+            }
+            "--boot-rom" => {
+                boot_rom_path = Some(args.next().expect("--boot-rom expects a boot ROM file"));
+            }
+            "--keys" => {
+                keys_path = Some(args.next().expect("--keys expects a key bindings file"));
+            }
+            "--record" => {
+                record_path = Some(args.next().expect("--record expects a movie file"));
+            }
+            "--play" => {
+                play_path = Some(args.next().expect("--play expects a movie file"));
+            }
+            "--headless" => headless = true,
+            _ => rom_path = arg,
+        }
+    }
+    if record_path.is_some() && play_path.is_some() {
+        eprintln!("Cannot record a movie while playing one back");
+        process::exit(1);
+    }
+    if headless && play_path.is_none() {
+        eprintln!("--headless only works when playing a movie back");
+        process::exit(1);
+    }
+
+    // a movie is played back on the model it was recorded on
+    let mut player = play_path.map(|path| {
+        let player = MoviePlayer::open(&path).unwrap_or_else(|err| {
+            eprintln!("Could not play {}: {}", path, err);
+            process::exit(1);
+        });
+        model = player.header().model;
+        player
+    });
+    // the boot ROM is checked against the model, once a movie had its say
+    let boot_rom = boot_rom_path.map(|path| {
+        bus::load_boot_rom(&path, model).unwrap_or_else(|err| {
+            eprintln!("Could not load boot ROM {}: {}", path, err);
+            process::exit(1);
+        })
+    });
+    let boot_rom_hash = boot_rom.as_deref().map(movie::hash);
+
+    let mut gameboy = match GameBoy::new(&rom_path, model, boot_rom) {
+        Ok(gameboy) => gameboy,
+        Err(err) => {
+            eprintln!("Could not load {}: {}", rom_path, err);
+            process::exit(1);
+        }
+    };
+    eprintln!("{}", gameboy.bus().cartridge_header());
+    if !gameboy.bus().is_header_checksum_valid() {
+        eprintln!("Warning: header checksum does not match, the boot ROM would lock up");
+    }
+    if !gameboy.bus().is_global_checksum_valid() {
+        eprintln!("Warning: global checksum does not match the cartridge content");
+    }
+    let rom_hash = movie::hash(gameboy.bus().cartridge_data());
+
+    let has_battery = gameboy.bus().cartridge_header().cartridge_type.battery;
+    let save_path = bus::save_file_path(&rom_path);
+    if let Some(player) = &player {
+        if let Err(err) = player.check(rom_hash, boot_rom_hash) {
+            eprintln!("Could not play movie: {}", err);
+            process::exit(1);
+        }
+        // start from the recorded cartridge RAM rather than the save file, with
+        // the clock as it was when recording so the game sees the same time
+        gameboy.bus_mut().load_save_data(&player.header().save);
+    } else if has_battery {
+        if let Ok(save) = fs::read(&save_path) {
+            gameboy.bus_mut().load_save_data(&save);
+            gameboy.bus_mut().catch_up_clock();
+        }
+    }
+    // the save file is left alone while playing a movie back
+    let write_saves = has_battery && player.is_none();
+
+    let mut recorder = record_path.map(|path| {
+        let header = MovieHeader {
+            rom_hash,
+            model,
+            boot_rom_hash,
+            save: gameboy.bus().save_data(),
+        };
+        MovieRecorder::create(&path, &header).unwrap_or_else(|err| {
+            eprintln!("Could not record {}: {}", path, err);
+            process::exit(1);
+        })
+    });
+
+    if headless {
+        if let Some(player) = player {
+            play_headless(&mut gameboy, player);
+        }
+        return;
+    }
+
+    // the default config file is optional, one given on the command line is not
+    let bindings = match keys_path {
+        Some(path) => KeyBindings::from_file(&path),
+        None if Path::new(KeyBindings::DEFAULT_PATH).exists() => {
+            KeyBindings::from_file(KeyBindings::DEFAULT_PATH)
+        }
+        None => Ok(KeyBindings::default()),
+    };
+    let bindings = bindings.unwrap_or_else(|err| {
+        eprintln!("Invalid key bindings: {}", err);
+        process::exit(1);
+    });
+    for conflict in bindings.conflicts() {
+        eprintln!("Warning: conflicting key bindings, {}", conflict);
+    }
+    let mut keys = Buttons::new(bindings);
 
     // let mut debugger = debugger::Debugger::new_debugger();
     //debugger.set_paused(true);
     //let _debug = false;
 
-    let mut canvas = Canvas::new(x_size, y_size);
-
     let mut window = Window::new(
         "GB Emulator",
         x_size,
@@ -50,17 +187,62 @@ fn main() {
         panic!("Couldn't create window: {}", err);
     });
 
-    window.limit_update_rate(Some(Duration::from_micros(16600)));
+    // frames are paced by the clock below rather than by minifb
+    window.limit_update_rate(None);
+    let frame_duration = Duration::from_nanos(GameBoy::FRAME_CYCLES * 1_000_000_000 / CLOCK_SPEED);
+    let mut next_frame = Instant::now();
 
     while window.is_open() && !window.is_key_down(Key::Escape) {
-        keys.update_keys(&window);
-        keys.update_register(&mut bus);
-        gpu.tick(&mut bus, &mut canvas, &mut window);
-        cpu.tick(&mut bus);
+        // recorded inputs replace the window ones until the movie is over
+        match player.as_mut().map(|player| player.next_frame()) {
+            Some(Some(state)) => keys.set_state(state),
+            Some(None) => {
+                eprintln!("Movie playback finished");
+                player = None;
+                keys.update_keys(&window);
+            }
+            None => keys.update_keys(&window),
+        }
+        if let Some(movie) = recorder.as_mut() {
+            if let Err(err) = movie.record_frame(keys.state()) {
+                eprintln!("Could not record movie, recording stopped: {}", err);
+                recorder = None;
+            }
+        }
+        keys.update_register(gameboy.bus_mut());
+        gameboy.run_frame();
+        if write_saves && gameboy.bus_mut().is_save_due() {
+            write_save_file(&save_path, gameboy.bus());
+        }
+        if let Some(rumble) = gameboy.bus_mut().poll_rumble() {
+            window.set_title(if rumble {
+                "GB Emulator [RUMBLE]"
+            } else {
+                "GB Emulator"
+            });
+        }
 
         window
-            .update_with_buffer(&canvas[..], x_size, y_size)
+            .update_with_buffer(&gameboy.screen()[..], x_size, y_size)
             .unwrap();
+
+        next_frame += frame_duration;
+        let now = Instant::now();
+        if next_frame > now {
+            thread::sleep(next_frame - now);
+        } else {
+            // running late, don't try to catch up on the lost frames
+            next_frame = now;
+        }
+    }
+
+    if write_saves {
+        write_save_file(&save_path, gameboy.bus());
+    }
+    if let Some(movie) = recorder {
+        if let Err(err) = movie.finish() {
+            eprintln!("Could not record movie: {}", err);
+        }
     }
 
     //'main_loop: loop {
@@ -87,3 +269,40 @@ fn main() {
     //}
     //}
 }
+
+// Run a movie as fast as possible, without a window. The screen hash at the
+// end tells whether two runs ended up in the same state
+fn play_headless(gameboy: &mut GameBoy, mut player: MoviePlayer) {
+    let mut keys = Buttons::new(KeyBindings::default());
+    let frames = player.frame_count();
+    while let Some(state) = player.next_frame() {
+        keys.set_state(state);
+        keys.update_register(gameboy.bus_mut());
+        gameboy.run_frame();
+    }
+
+    let screen: Vec<u8> = gameboy.screen()[..]
+        .iter()
+        .flat_map(|pixel| pixel.to_le_bytes())
+        .collect();
+    println!(
+        "Played {} frames, screen hash {:016x}",
+        frames,
+        movie::hash(&screen)
+    );
+}
+
+// The save goes to a temporary file first, so that a crash or a full disk
+// while writing can't leave a truncated save in place of the previous one
+fn write_save_file(path: &Path, bus: &bus::Bus) {
+    let save = bus.save_data();
+    if save.is_empty() {
+        return;
+    }
+    let temp_path = path.with_extension("sav.tmp");
+    let result = fs::write(&temp_path, save).and_then(|_| fs::rename(&temp_path, path));
+    if let Err(err) = result {
+        eprintln!("Could not write {}: {}", path.display(), err);
+        let _ = fs::remove_file(&temp_path);
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Game Boy hardware revisions, they differ in the state left behind by the boot ROM
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum Model {
+    DMG0,
+    #[default]
+    DMG,
+    MGB,
+    SGB,
+    SGB2,
+    CGB,
+}
+
+impl Model {
+    pub fn is_cgb(&self) -> bool {
+        matches!(self, Model::CGB)
+    }
+
+    pub fn is_sgb(&self) -> bool {
+        matches!(self, Model::SGB | Model::SGB2)
+    }
+}
+
+impl FromStr for Model {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name.to_lowercase().as_str() {
+            "dmg0" => Ok(Model::DMG0),
+            "dmg" => Ok(Model::DMG),
+            "mgb" => Ok(Model::MGB),
+            "sgb" => Ok(Model::SGB),
+            "sgb2" => Ok(Model::SGB2),
+            "cgb" => Ok(Model::CGB),
+            _ => Err(format!("Unknown hardware model: {}", name)),
+        }
+    }
+}
+
+impl fmt::Display for Model {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Model::DMG0 => "dmg0",
+            Model::DMG => "dmg",
+            Model::MGB => "mgb",
+            Model::SGB => "sgb",
+            Model::SGB2 => "sgb2",
+            Model::CGB => "cgb",
+        };
+        write!(f, "{}", name)
+    }
+}
@@ -0,0 +1,310 @@
+use std::fmt::{self, Display, Formatter};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+
+use crate::model::Model;
+
+// Movies replay a session exactly: the header identifies the ROM and the state
+// the console started in, then each line holds the joypad state of a frame.
+//
+//   GBMOVIE 1
+//   rom 5a1c0f3b29e0d847
+//   model dmg
+//   boot_rom none
+//   save 00ff...
+//   frames
+//   ff
+//   fe
+//   ...
+const MAGIC: &str = "GBMOVIE 1";
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    Parse {
+        line: usize,
+        reason: String,
+    },
+    RomMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    BootRomMismatch {
+        expected: Option<u64>,
+        actual: Option<u64>,
+    },
+}
+
+impl Display for MovieError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            MovieError::Io(err) => write!(f, "could not access movie: {}", err),
+            MovieError::Parse { line, reason } => write!(f, "line {}: {}", line, reason),
+            MovieError::RomMismatch { expected, actual } => write!(
+                f,
+                "movie was recorded with another ROM: expected hash {:016x}, got {:016x}",
+                expected, actual
+            ),
+            MovieError::BootRomMismatch { expected, actual } => write!(
+                f,
+                "movie was recorded with another boot ROM: expected {}, got {}",
+                hash_name(*expected),
+                hash_name(*actual)
+            ),
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(err: io::Error) -> Self {
+        MovieError::Io(err)
+    }
+}
+
+// 64 bit FNV-1a, enough to tell ROM dumps apart
+pub fn hash(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+fn hash_name(hash: Option<u64>) -> String {
+    hash.map_or(String::from("none"), |hash| format!("{:016x}", hash))
+}
+
+// What the console is started from
+pub struct MovieHeader {
+    pub rom_hash: u64,
+    pub model: Model,
+    pub boot_rom_hash: Option<u64>,
+    pub save: Vec<u8>, // cartridge RAM, empty without battery
+}
+
+impl MovieHeader {
+    fn write(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", MAGIC)?;
+        writeln!(out, "rom {:016x}", self.rom_hash)?;
+        writeln!(out, "model {}", self.model)?;
+        writeln!(out, "boot_rom {}", hash_name(self.boot_rom_hash))?;
+        let save: String = self
+            .save
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        writeln!(out, "save {}", save)?;
+        writeln!(out, "frames")
+    }
+}
+
+pub struct MovieRecorder {
+    out: BufWriter<File>,
+}
+
+impl MovieRecorder {
+    pub fn create(path: &str, header: &MovieHeader) -> Result<MovieRecorder, MovieError> {
+        let mut out = BufWriter::new(File::create(path)?);
+        header.write(&mut out)?;
+        Ok(MovieRecorder { out })
+    }
+
+    // Joypad state of the frame about to run, see `Buttons::state`
+    pub fn record_frame(&mut self, joypad: u8) -> Result<(), MovieError> {
+        writeln!(self.out, "{:02x}", joypad)?;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<(), MovieError> {
+        self.out.flush()?;
+        Ok(())
+    }
+}
+
+pub struct MoviePlayer {
+    header: MovieHeader,
+    frames: Vec<u8>,
+    position: usize,
+}
+
+impl MoviePlayer {
+    pub fn open(path: &str) -> Result<MoviePlayer, MovieError> {
+        MoviePlayer::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(content: &str) -> Result<MoviePlayer, MovieError> {
+        let mut lines = content.lines().enumerate();
+        let mut next_line = |expected: &str| -> Result<(usize, String), MovieError> {
+            let (index, line) = lines.next().ok_or(MovieError::Parse {
+                line: 0,
+                reason: format!("missing {}", expected),
+            })?;
+            let value = line.strip_prefix(expected).ok_or(MovieError::Parse {
+                line: index + 1,
+                reason: format!("expected {}", expected),
+            })?;
+            Ok((index + 1, value.trim().to_string()))
+        };
+        let error = |line: usize, reason: String| MovieError::Parse { line, reason };
+        let parse_hash = |line: usize, value: &str| {
+            u64::from_str_radix(value, 16)
+                .map_err(|_| error(line, format!("invalid hash {}", value)))
+        };
+
+        next_line(MAGIC)?;
+        let (line, rom) = next_line("rom")?;
+        let rom_hash = parse_hash(line, &rom)?;
+        let (line, model) = next_line("model")?;
+        let model = model.parse().map_err(|err| error(line, err))?;
+        let (line, boot_rom) = next_line("boot_rom")?;
+        let boot_rom_hash = match boot_rom.as_str() {
+            "none" => None,
+            hash => Some(parse_hash(line, hash)?),
+        };
+        let (line, save) = next_line("save")?;
+        let save = parse_bytes(&save).ok_or_else(|| error(line, String::from("invalid save")))?;
+        next_line("frames")?;
+
+        let mut frames = Vec::new();
+        for (index, line) in lines {
+            let joypad = u8::from_str_radix(line.trim(), 16)
+                .map_err(|_| error(index + 1, format!("invalid joypad state {}", line)))?;
+            frames.push(joypad);
+        }
+
+        Ok(MoviePlayer {
+            header: MovieHeader {
+                rom_hash,
+                model,
+                boot_rom_hash,
+                save,
+            },
+            frames,
+            position: 0,
+        })
+    }
+
+    pub fn header(&self) -> &MovieHeader {
+        &self.header
+    }
+
+    // Make sure the console is started the way it was when recording
+    pub fn check(&self, rom_hash: u64, boot_rom_hash: Option<u64>) -> Result<(), MovieError> {
+        if rom_hash != self.header.rom_hash {
+            return Err(MovieError::RomMismatch {
+                expected: self.header.rom_hash,
+                actual: rom_hash,
+            });
+        }
+        if boot_rom_hash != self.header.boot_rom_hash {
+            return Err(MovieError::BootRomMismatch {
+                expected: self.header.boot_rom_hash,
+                actual: boot_rom_hash,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    // Joypad state of the next frame, None once the movie is over
+    pub fn next_frame(&mut self) -> Option<u8> {
+        let joypad = self.frames.get(self.position).copied()?;
+        self.position += 1;
+        Some(joypad)
+    }
+}
+
+fn parse_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(hex.get(index..index + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOVIE: &str =
+        "GBMOVIE 1\nrom 5a1c0f3b29e0d847\nmodel dmg\nboot_rom none\nsave \nframes\nff\nfe\n";
+
+    fn parse_error(content: &str) -> (usize, String) {
+        match MoviePlayer::parse(content) {
+            Err(MovieError::Parse { line, reason }) => (line, reason),
+            Err(err) => panic!("unexpected error {}", err),
+            Ok(_) => panic!("movie should not parse"),
+        }
+    }
+
+    #[test]
+    fn plays_back_recorded_movie() {
+        let path = std::env::temp_dir().join(format!("gbmovie-{}.txt", std::process::id()));
+        let path = path.to_str().unwrap();
+        let header = MovieHeader {
+            rom_hash: hash(b"rom"),
+            model: Model::default(),
+            boot_rom_hash: Some(hash(b"boot")),
+            save: vec![0x00, 0x7F, 0xFF],
+        };
+        let mut recorder = MovieRecorder::create(path, &header).unwrap();
+        for &joypad in &[0xFF, 0xEF, 0x00] {
+            recorder.record_frame(joypad).unwrap();
+        }
+        recorder.finish().unwrap();
+
+        let player = MoviePlayer::open(path);
+        fs::remove_file(path).unwrap();
+        let mut player = player.unwrap();
+        assert_eq!(player.header().rom_hash, header.rom_hash);
+        assert_eq!(player.header().model, header.model);
+        assert_eq!(player.header().boot_rom_hash, header.boot_rom_hash);
+        assert_eq!(player.header().save, header.save);
+        assert_eq!(player.frame_count(), 3);
+        let frames: Vec<u8> = std::iter::from_fn(|| player.next_frame()).collect();
+        assert_eq!(frames, [0xFF, 0xEF, 0x00]);
+    }
+
+    #[test]
+    fn rejects_bad_magic() {
+        assert_eq!(parse_error("GBMOVIE 2\n").0, 1);
+        assert_eq!(parse_error(&MOVIE.replace("GBMOVIE", "MOVIE")).0, 1);
+    }
+
+    #[test]
+    fn rejects_truncated_movie() {
+        assert_eq!(parse_error("").0, 0);
+        let header_end = MOVIE.find("frames").unwrap();
+        let (line, reason) = parse_error(&MOVIE[..header_end]);
+        assert_eq!((line, reason.as_str()), (0, "missing frames"));
+        // a frame line cut in the middle of a byte
+        assert_eq!(parse_error(&format!("{}f0\nz", MOVIE)).0, 10);
+    }
+
+    #[test]
+    fn rejects_invalid_header_values() {
+        assert_eq!(parse_error(&MOVIE.replace("dmg", "gba")).0, 3);
+        assert_eq!(parse_error(&MOVIE.replace("save ", "save 0")).0, 5);
+        assert_eq!(parse_error(&MOVIE.replace("rom 5a", "rom x5a")).0, 2);
+    }
+
+    #[test]
+    fn checks_rom_and_boot_rom() {
+        let player = MoviePlayer::parse(MOVIE).unwrap();
+        assert_eq!(player.frame_count(), 2);
+        assert!(player.check(0x5a1c0f3b29e0d847, None).is_ok());
+        match player.check(1, None) {
+            Err(MovieError::RomMismatch { expected, actual }) => {
+                assert_eq!((expected, actual), (0x5a1c0f3b29e0d847, 1))
+            }
+            _ => panic!("ROM mismatch not detected"),
+        }
+        assert!(matches!(
+            player.check(0x5a1c0f3b29e0d847, Some(2)),
+            Err(MovieError::BootRomMismatch { .. })
+        ));
+    }
+}